@@ -1,64 +1,78 @@
 extern crate rustyweb;
 
 use std::net::TcpStream;
-use std::io::{Write, BufWriter, Error, ErrorKind};
-use serde_json;
+use std::io::Error;
+use std::sync::Arc;
 
 use rustyweb::web::{server, websocket};
-use rustyweb::parser;
-use rustyweb::http;
-use rustyweb::http::request::{Request, Method};
+use rustyweb::web::router::Router;
+use rustyweb::web::hub::Hub;
+use rustyweb::http::deflate;
+use rustyweb::http::request::Request;
 use rustyweb::http::response;
+use rustyweb::http::websocket::{Message, CloseCode};
+use rustyweb::web::websocket::{Handshake, Events, Sender};
+use rustyweb::web::websocket::codec::{Codec, Json as JsonCodec};
 
-type JSON = serde_json::Value;
+type Json = serde_json::Value;
 
-fn main() {
-    server::serve("0.0.0.0", 8080, respond)
+fn main() -> Result<(), Error> {
+    let router = Router::new()
+        .get("/", index)
+        .get("/bundle.js", bundle)
+        .get("/ws", ws);
+
+    server::serve("0.0.0.0", 8080, server::with_state(Hub::new(), router))?.join();
+
+    Ok(())
 }
 
-fn respond(stream: &TcpStream, request: Request) -> Result<(), Error> {
-    let headers = vec!["Content-Type: text/html; charset=utf-8".to_string()];
+fn index(stream: &TcpStream, _request: Request) -> Result<(), Error> {
+    server::respond(stream,
+                    response::asset("index.html", include_bytes!("../client/dist/index.html")))
+}
+
+fn bundle(stream: &TcpStream, _request: Request) -> Result<(), Error> {
+    server::respond(stream,
+                    response::asset("bundle.js", include_bytes!("../client/dist/bundle.js")))
+}
 
-    match (request.get_method_and_uri(), request.is_websocket_upgrade()) {
-        ((Method::GET, "/"), false) =>
-            server::respond(stream,
-                            response::ok(include_str!("../client/dist/index.html"), headers)),
-        ((Method::GET, "/bundle.js"), false) =>
-            server::respond(stream,
-                            response::ok(include_str!("../client/dist/bundle.js"), headers)),
-        ((Method::GET, "/ws"), true) =>
-            websocket::echo_chamber(stream, request, EchoChamber {}),
-        _ =>
-            Err(Error::new(ErrorKind::NotFound, "404"))
+fn ws(stream: &TcpStream, request: Request) -> Result<(), Error> {
+    match (request.is_websocket_upgrade(), request.state::<Hub>()) {
+        (true, Some(hub)) => websocket::session(stream, request, Subscriber { hub }),
+        _ => server::respond(stream, response::bad_request())
     }
 }
 
-struct EchoChamber {}
+/// Passes JSON sent by one client on to every connected client
+struct Subscriber {
+    hub: Arc<Hub>
+}
 
-impl websocket::Communicator<JSON> for EchoChamber {
-    fn protocol(&self) -> &str{
-        "json"
+impl Handshake for Subscriber {
+    fn protocols(&self) -> &[&str] {
+        &["json"]
     }
 
-    fn receive(&self, stream: &TcpStream) -> Result<Option<JSON>, Error> {
-        match parser::websocket::parse(stream) {
-            Ok(Some(msg)) =>
-                Ok(Some(serde_json::from_str(&String::from_utf8(msg).unwrap()).unwrap())),
-            Ok(None) => Ok(None),
-            Err(err) => Err(err)
-        }
+    fn deflate(&self) -> Option<deflate::Config> {
+        Some(deflate::Config::default())
     }
+}
 
-    fn send(&self, stream: &TcpStream, msg: JSON) -> Result<(), Error> {
-        println!("{:?}", msg);
-        let payload = http::websocket::Frame::new(serde_json::to_vec(&msg).unwrap(),
-                                                  http::websocket::Opcode::TEXT);
+impl Events for Subscriber {
+    /// Hub drops the session on its own once it has ended
+    fn on_open(&self, sender: &Sender) {
+        self.hub.add(sender);
+    }
 
-        let mut writer = BufWriter::new(stream);
+    fn on_message(&self, sender: &Sender, msg: Message) {
+        let json: Result<Json, _> = JsonCodec.decode(msg);
 
-        match writer.write(&payload.payload) {
-            Ok(_) => Ok(()),
-            Err(err) => Err(err)
+        match json {
+            Ok(json) => {
+                self.hub.broadcast(Message::Text(json.to_string()));
+            },
+            Err(_) => sender.close(CloseCode::INVALID_PAYLOAD, "Invalid JSON").unwrap_or_default()
         }
     }
 }
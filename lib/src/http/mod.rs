@@ -8,114 +8,1331 @@ pub mod websocket {
     // %x9 denotes a ping
     // %xA denotes a pong
     // %xB-F are reserved for further control frames
-    #[derive(Debug)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub enum Opcode {
-        // CONTINUATION = 0,
+        CONTINUATION = 0,
         TEXT = 1,
-        // BINARY = 2,
+        BINARY = 2,
         CLOSE = 8,
-        // PING = 9,
-        // PONG = 10
+        PING = 9,
+        PONG = 10
+    }
+
+    impl Opcode {
+        /// None for reserved opcodes
+        pub fn from_u8(opcode: u8) -> Option<Opcode> {
+            match opcode {
+                0 => Some(Opcode::CONTINUATION),
+                1 => Some(Opcode::TEXT),
+                2 => Some(Opcode::BINARY),
+                8 => Some(Opcode::CLOSE),
+                9 => Some(Opcode::PING),
+                10 => Some(Opcode::PONG),
+                _ => None
+            }
+        }
+
+        pub fn is_control(&self) -> bool {
+            (*self as u8) & 0x8 != 0
+        }
+    }
+
+    /// Status code sent in a close frame (RFC 6455 section 7.4)
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct CloseCode(u16);
+
+    impl CloseCode {
+        pub const NORMAL: CloseCode = CloseCode(1000);
+        pub const GOING_AWAY: CloseCode = CloseCode(1001);
+        pub const PROTOCOL_ERROR: CloseCode = CloseCode(1002);
+        pub const UNSUPPORTED_DATA: CloseCode = CloseCode(1003);
+        /// Never sent, stands for a close frame without status code
+        pub const NO_STATUS: CloseCode = CloseCode(1005);
+        /// Never sent, stands for a connection closed without a close frame
+        pub const ABNORMAL: CloseCode = CloseCode(1006);
+        pub const INVALID_PAYLOAD: CloseCode = CloseCode(1007);
+        pub const POLICY_VIOLATION: CloseCode = CloseCode(1008);
+        pub const MESSAGE_TOO_BIG: CloseCode = CloseCode(1009);
+        pub const MANDATORY_EXTENSION: CloseCode = CloseCode(1010);
+        pub const INTERNAL_ERROR: CloseCode = CloseCode(1011);
+
+        pub fn from_u16(code: u16) -> CloseCode {
+            CloseCode(code)
+        }
+
+        pub fn code(&self) -> u16 {
+            self.0
+        }
+
+        /// Whether the code may appear in a close frame: codes defined by RFC 6455
+        /// and the IANA registry, or ones reserved for libraries and applications
+        pub fn is_valid(&self) -> bool {
+            matches!(self.0, 1000..=1003 | 1007..=1014 | 3000..=4999)
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct CloseFrame {
+        pub code: CloseCode,
+        pub reason: String
+    }
+
+    impl CloseFrame {
+        pub fn new(code: CloseCode, reason: &str) -> CloseFrame {
+            CloseFrame {
+                code,
+                reason: reason.to_string()
+            }
+        }
+
+        /// None for a close frame without status code
+        pub fn from_payload(payload: &[u8]) -> Option<CloseFrame> {
+            match payload.len() {
+                0 | 1 => None,
+                _ => Some(CloseFrame {
+                    code: CloseCode(u16::from_be_bytes([payload[0], payload[1]])),
+                    reason: String::from_utf8_lossy(&payload[2..]).to_string()
+                })
+            }
+        }
+
+        /// Status code followed by the reason, cut to fit in a control frame
+        pub fn to_payload(&self) -> Vec<u8> {
+            let mut end = self.reason.len().min(123);
+
+            while !self.reason.is_char_boundary(end) {
+                end -= 1;
+            }
+
+            [&self.code.0.to_be_bytes()[..], &self.reason.as_bytes()[..end]].concat()
+        }
+    }
+
+    /// Message received from or sent to the other endpoint
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum Message {
+        Text(String),
+        Binary(Vec<u8>),
+        Close(Option<CloseFrame>)
+    }
+
+    impl From<Message> for Frame {
+        fn from(msg: Message) -> Frame {
+            match msg {
+                Message::Text(text) => Frame::new(text.into_bytes(), Opcode::TEXT),
+                Message::Binary(data) => Frame::new(data, Opcode::BINARY),
+                Message::Close(close) => Frame::close(close.as_ref())
+            }
+        }
+    }
+
+    pub struct Frame {
+        pub payload: Vec<u8>
+    }
+
+    impl Frame {
+        pub fn new(msg: Vec<u8>, opcode: Opcode) -> Frame {
+            Frame::with_rsv(msg, opcode, 0)
+        }
+
+        /// Data frame with a payload compressed by permessage-deflate, marked with RSV1
+        pub fn compressed(msg: Vec<u8>, opcode: Opcode) -> Frame {
+            Frame::with_rsv(msg, opcode, 0x4)
+        }
+
+        fn with_rsv(mut msg: Vec<u8>, opcode: Opcode, rsv: u8) -> Frame {
+            let first_byte = 0x80 | rsv << 4 | opcode as u8;
+
+            let payload = match msg.len() {
+                length if length < 126 => {
+                    let mut header =  vec![first_byte, length as u8];
+                    header.append(&mut msg);
+
+                    header
+                },
+                length if length < 65536 => {
+                    let mut header = vec![first_byte, 126];
+                    header.append(&mut (length as u16).to_be_bytes().to_vec());
+                    header.append(&mut msg);
+
+                    header
+                },
+                length => {
+                    let mut header = vec![first_byte, 127];
+                    header.append(&mut (length as u64).to_be_bytes().to_vec());
+                    header.append(&mut msg);
+
+                    header
+                }
+            };
+
+            Frame { payload }
+        }
+
+        /// Mask frame as clients do, payload is XORed with the key
+        pub fn masked(mut self, key: [u8; 4]) -> Frame {
+            let header_length = match self.payload[1] & 0x7F {
+                126 => 4,
+                127 => 10,
+                _ => 2
+            };
+
+            let data = self.payload.split_off(header_length);
+
+            self.payload[1] |= 0x80;
+            self.payload.extend_from_slice(&key);
+            self.payload.append(&mut unmask_payload(data, Some(key)));
+
+            self
+        }
+
+        /// Close frame, without payload when there's no status code to send
+        pub fn close(close: Option<&CloseFrame>) -> Frame {
+            match close {
+                Some(close) if close.code != CloseCode::NO_STATUS && close.code != CloseCode::ABNORMAL =>
+                    Frame::new(close.to_payload(), Opcode::CLOSE),
+                _ => Frame::new(vec![], Opcode::CLOSE)
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct Header {
+        pub is_final_frame: bool,
+        /// RSV1, RSV2 and RSV3 bits, reserved for extensions
+        pub rsv: u8,
+        pub opcode: Opcode,
+        pub is_masked: bool,
+        pub payload_length: usize
+    }
+
+    impl Header {
+        pub fn new(is_final_frame: bool,
+                   opcode: Opcode,
+                   is_masked: bool,
+                   payload_length: usize) -> Header {
+            Header {
+                is_final_frame,
+                rsv: 0,
+                opcode,
+                is_masked,
+                payload_length
+            }
+        }
+    }
+
+    /// Random key for masking a frame sent by a client
+    pub fn masking_key() -> [u8; 4] {
+        rand::random()
+    }
+
+    pub fn unmask_payload(payload: Vec<u8>, masking_key: Option<[u8; 4]>) -> Vec<u8> {
+        match masking_key {
+            Some(key) => payload.iter()
+                    .enumerate()
+                    .map(|(i, val)| val ^ key[i % 4])
+                    .collect(),
+            None => payload
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_close_frame_payload() {
+            let close = CloseFrame::new(CloseCode::GOING_AWAY, "bye");
+
+            assert_eq!(close.to_payload(), vec![0x03, 0xE9, b'b', b'y', b'e']);
+            assert_eq!(CloseFrame::from_payload(&close.to_payload()), Some(close));
+            assert_eq!(CloseFrame::from_payload(&[]), None);
+        }
+
+        #[test]
+        fn test_close_frame_long_reason() {
+            let close = CloseFrame::new(CloseCode::NORMAL, &"ä".repeat(100));
+
+            assert_eq!(close.to_payload().len(), 124);
+            assert_eq!(Frame::close(Some(&close)).payload.len(), 126);
+        }
+
+        #[test]
+        fn test_close_frame_without_status() {
+            let close = CloseFrame::new(CloseCode::NO_STATUS, "");
+
+            assert_eq!(Frame::close(Some(&close)).payload, vec![0x88, 0x00]);
+            assert_eq!(Frame::close(None).payload, vec![0x88, 0x00]);
+        }
+
+        #[test]
+        fn test_masked_frame() {
+            // RFC 6455 section 5.7, masked "Hello"
+            assert_eq!(Frame::new(b"Hello".to_vec(), Opcode::TEXT).masked([0x37, 0xfa, 0x21, 0x3d]).payload,
+                       vec![0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58]);
+
+            let masked = Frame::new(vec![0; 256], Opcode::BINARY).masked([1, 2, 3, 4]).payload;
+
+            assert_eq!(masked[..8], [0x82, 0xFE, 0x01, 0x00, 1, 2, 3, 4]);
+            assert_eq!(masked[8..12], [1, 2, 3, 4]);
+        }
+    }
+}
+
+/// permessage-deflate extension (RFC 7692): messages are compressed with raw
+/// DEFLATE, the trailing empty block of a sync flush left out
+pub mod deflate {
+    use std::io::{Error, ErrorKind};
+    use flate2::{Compress, Decompress, Compression, FlushCompress, FlushDecompress};
+
+    pub const EXTENSION: &str = "permessage-deflate";
+
+    const TAIL: [u8; 4] = [0x00, 0x00, 0xFF, 0xFF];
+    const MIN_WINDOW_BITS: u8 = 9;
+    const MAX_WINDOW_BITS: u8 = 15;
+
+    /// Extension parameters, either the preferences of the server or ones agreed on
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Config {
+        /// LZ77 window of the server compressor, 9 to 15 bits
+        pub server_max_window_bits: u8,
+        /// LZ77 window asked from the client compressor when it supports limiting it
+        pub client_max_window_bits: u8,
+        /// Server compresses every message on its own, saving memory between messages
+        pub server_no_context_takeover: bool,
+        pub client_no_context_takeover: bool,
+        /// Whether the client offered client_max_window_bits, i.e. it can be answered
+        client_max_window_bits_offered: bool
+    }
+
+    impl Default for Config {
+        fn default() -> Config {
+            Config {
+                server_max_window_bits: MAX_WINDOW_BITS,
+                client_max_window_bits: MAX_WINDOW_BITS,
+                server_no_context_takeover: false,
+                client_no_context_takeover: false,
+                client_max_window_bits_offered: false
+            }
+        }
+    }
+
+    impl Config {
+        /// Accept first offer of Sec-WebSocket-Extensions we can fulfill, with our
+        /// preferences applied. None when permessage-deflate wasn't offered or no
+        /// offer is acceptable, the connection is then uncompressed
+        pub fn negotiate(&self, offers: &str) -> Option<Config> {
+            offers.split(',').find_map(|offer| self.accept(offer))
+        }
+
+        fn accept(&self, offer: &str) -> Option<Config> {
+            let mut params = offer.split(';').map(|param| param.trim());
+
+            if params.next()? != EXTENSION {
+                return None;
+            }
+
+            let mut agreed = self.clone();
+            let mut seen = vec![];
+
+            for param in params {
+                let (name, value) = match param.find('=') {
+                    Some(i) => (param[..i].trim(), Some(param[i + 1..].trim().trim_matches('"'))),
+                    None => (param, None)
+                };
+
+                // parameters may appear only once
+                if seen.contains(&name) {
+                    return None;
+                }
+
+                seen.push(name);
+
+                match (name, value) {
+                    ("server_no_context_takeover", None) => agreed.server_no_context_takeover = true,
+                    ("client_no_context_takeover", None) => agreed.client_no_context_takeover = true,
+                    ("server_max_window_bits", Some(bits)) => {
+                        agreed.server_max_window_bits = agreed.server_max_window_bits
+                            .min(parse_window_bits(bits)?);
+                    },
+                    ("client_max_window_bits", bits) => {
+                        let bits = match bits {
+                            Some(bits) => parse_window_bits(bits)?,
+                            None => MAX_WINDOW_BITS
+                        };
+
+                        agreed.client_max_window_bits = agreed.client_max_window_bits.min(bits);
+                        agreed.client_max_window_bits_offered = true;
+                    },
+                    _ => return None
+                }
+            }
+
+            if !agreed.client_max_window_bits_offered {
+                agreed.client_max_window_bits = MAX_WINDOW_BITS;
+            }
+
+            // the compressor can't go below 9 bits
+            match agreed.server_max_window_bits >= MIN_WINDOW_BITS {
+                true => Some(agreed),
+                false => None
+            }
+        }
+
+        /// Value of Sec-WebSocket-Extensions in the response accepting the offer
+        pub fn to_header(&self) -> String {
+            let mut header = EXTENSION.to_string();
+
+            if self.server_no_context_takeover {
+                header.push_str("; server_no_context_takeover");
+            }
+
+            if self.client_no_context_takeover {
+                header.push_str("; client_no_context_takeover");
+            }
+
+            if self.server_max_window_bits < MAX_WINDOW_BITS {
+                header.push_str(&format!("; server_max_window_bits={}", self.server_max_window_bits));
+            }
+
+            if self.client_max_window_bits_offered && self.client_max_window_bits < MAX_WINDOW_BITS {
+                header.push_str(&format!("; client_max_window_bits={}", self.client_max_window_bits));
+            }
+
+            header
+        }
+
+        /// Compressor for messages sent by the server
+        pub fn deflater(&self) -> Deflater {
+            Deflater {
+                compress: Compress::new_with_window_bits(Compression::default(),
+                                                         false,
+                                                         self.server_max_window_bits.max(MIN_WINDOW_BITS)),
+                no_context_takeover: self.server_no_context_takeover
+            }
+        }
+
+        /// Decompressor for messages sent by the client
+        pub fn inflater(&self) -> Inflater {
+            Inflater {
+                decompress: Decompress::new_with_window_bits(false, MAX_WINDOW_BITS),
+                no_context_takeover: self.client_no_context_takeover
+            }
+        }
+    }
+
+    fn parse_window_bits(bits: &str) -> Option<u8> {
+        match bits.parse() {
+            Ok(parsed) if !bits.starts_with('0') && (8..=MAX_WINDOW_BITS).contains(&parsed) =>
+                Some(parsed),
+            _ => None
+        }
+    }
+
+    pub struct Deflater {
+        compress: Compress,
+        no_context_takeover: bool
+    }
+
+    impl Deflater {
+        pub fn compress(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
+            let start = self.compress.total_in();
+            let mut output = Vec::with_capacity(data.len() / 2 + 64);
+
+            loop {
+                let consumed = (self.compress.total_in() - start) as usize;
+
+                self.compress.compress_vec(&data[consumed..], &mut output, FlushCompress::Sync)
+                    .map_err(Error::other)?;
+
+                let consumed = (self.compress.total_in() - start) as usize;
+
+                // sync flush is done once there's room left over in the output
+                if consumed == data.len() && output.len() < output.capacity() {
+                    break;
+                }
+
+                output.reserve(output.capacity().max(64));
+            }
+
+            if self.no_context_takeover {
+                self.compress.reset();
+            }
+
+            if output.ends_with(&TAIL) {
+                output.truncate(output.len() - TAIL.len());
+            }
+
+            Ok(output)
+        }
+    }
+
+    pub struct Inflater {
+        decompress: Decompress,
+        no_context_takeover: bool
+    }
+
+    impl Inflater {
+        /// Decompress one message. Stops after max_size + 1 bytes, so the
+        /// caller can tell the message was too big without inflating all of it
+        pub fn decompress(&mut self, data: &[u8], max_size: usize) -> Result<Vec<u8>, Error> {
+            let input = [data, &TAIL].concat();
+            let start = self.decompress.total_in();
+            let mut output = Vec::with_capacity(input.len().saturating_mul(2).min(max_size.saturating_add(1)));
+
+            loop {
+                let consumed = (self.decompress.total_in() - start) as usize;
+                let produced = output.len();
+
+                if output.len() == output.capacity() {
+                    output.reserve(output.capacity().max(1024).min(max_size.saturating_add(1) - output.len()));
+                }
+
+                self.decompress.decompress_vec(&input[consumed..], &mut output, FlushDecompress::Sync)
+                    .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+
+                let done = (self.decompress.total_in() - start) as usize == input.len()
+                    && output.len() < output.capacity();
+
+                if done || output.len() > max_size {
+                    break;
+                }
+
+                if (self.decompress.total_in() - start) as usize == consumed && output.len() == produced {
+                    return Err(Error::new(ErrorKind::InvalidData, "Truncated compressed message"));
+                }
+            }
+
+            if self.no_context_takeover {
+                self.decompress.reset(false);
+            }
+
+            Ok(output)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_negotiate_default() {
+            let config = Config::default();
+            let agreed = config.negotiate("permessage-deflate; client_max_window_bits").unwrap();
+
+            assert_eq!(agreed.to_header(), "permessage-deflate");
+        }
+
+        #[test]
+        fn test_negotiate_parameters() {
+            let config = Config { client_max_window_bits: 12, ..Config::default() };
+            let agreed = config.negotiate("permessage-deflate; server_no_context_takeover; \
+                                           client_max_window_bits; server_max_window_bits=10").unwrap();
+
+            assert_eq!(agreed.to_header(),
+                       "permessage-deflate; server_no_context_takeover; \
+                        server_max_window_bits=10; client_max_window_bits=12");
+        }
+
+        #[test]
+        fn test_negotiate_falls_back_to_next_offer() {
+            let config = Config::default();
+            let agreed = config.negotiate("x-webkit-deflate-frame, \
+                                           permessage-deflate; server_max_window_bits=8, \
+                                           permessage-deflate; unknown, \
+                                           permessage-deflate; server_max_window_bits=\"11\"").unwrap();
+
+            assert_eq!(agreed.server_max_window_bits, 11);
+        }
+
+        #[test]
+        fn test_negotiate_declines() {
+            let config = Config::default();
+
+            assert_eq!(config.negotiate("x-webkit-deflate-frame"), None);
+            assert_eq!(config.negotiate("permessage-deflate; server_max_window_bits"), None);
+            assert_eq!(config.negotiate("permessage-deflate; server_max_window_bits=16"), None);
+            assert_eq!(config.negotiate("permessage-deflate; server_no_context_takeover; \
+                                         server_no_context_takeover"), None);
+        }
+
+        #[test]
+        fn test_compress_rfc_example() {
+            // RFC 7692 section 7.2.3.1, "Hello" compressed
+            let mut deflater = Config::default().deflater();
+            let mut inflater = Config::default().inflater();
+
+            let compressed = deflater.compress(b"Hello").unwrap();
+
+            assert!(!compressed.ends_with(&TAIL));
+            assert_eq!(inflater.decompress(&compressed, 1024).unwrap(), b"Hello");
+            assert_eq!(inflater.decompress(&[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00], 1024).unwrap(),
+                       b"Hello");
+        }
+
+        #[test]
+        fn test_compress_context_takeover() {
+            let mut deflater = Config::default().deflater();
+            let mut inflater = Config::default().inflater();
+            let message = "{\"query\": \"select * from table\"}".repeat(10);
+
+            let first = deflater.compress(message.as_bytes()).unwrap();
+            let second = deflater.compress(message.as_bytes()).unwrap();
+
+            // second message refers back to the first one
+            assert!(second.len() < first.len());
+            assert_eq!(inflater.decompress(&first, 1024).unwrap(), message.as_bytes());
+            assert_eq!(inflater.decompress(&second, 1024).unwrap(), message.as_bytes());
+        }
+
+        #[test]
+        fn test_compress_no_context_takeover() {
+            let config = Config { server_no_context_takeover: true, ..Config::default() };
+            let mut deflater = config.deflater();
+
+            let first = deflater.compress(b"Hello, Hello").unwrap();
+            let second = deflater.compress(b"Hello, Hello").unwrap();
+
+            assert_eq!(first, second);
+            assert_eq!(Config::default().inflater().decompress(&second, 1024).unwrap(), b"Hello, Hello");
+        }
+
+        #[test]
+        fn test_decompress_limit() {
+            let compressed = Config::default().deflater().compress(&[0; 100000]).unwrap();
+            let mut inflater = Config::default().inflater();
+
+            assert_eq!(inflater.decompress(&compressed, 1000).unwrap().len(), 1001);
+            assert_eq!(Config::default().inflater().decompress(&compressed, usize::MAX).unwrap().len(), 100000);
+        }
+
+        #[test]
+        fn test_decompress_invalid() {
+            assert!(Config::default().inflater().decompress(&[0xFF, 0xFF, 0xFF], 1024).is_err());
+        }
+    }
+}
+
+pub mod mime {
+    /// Used for unknown extensions, so browsers won't try to render the content
+    pub const DEFAULT: &str = "application/octet-stream";
+
+    /// MIME type for a file extension (without the dot), case-insensitively
+    pub fn from_extension(extension: &str) -> Option<&'static str> {
+        let mime = match extension.to_ascii_lowercase().as_ref() {
+            "html" | "htm" => "text/html; charset=utf-8",
+            "css" => "text/css; charset=utf-8",
+            "js" | "mjs" => "text/javascript; charset=utf-8",
+            "json" | "map" => "application/json",
+            "txt" => "text/plain; charset=utf-8",
+            "csv" => "text/csv; charset=utf-8",
+            "xml" => "application/xml",
+            "md" => "text/markdown; charset=utf-8",
+            "png" => "image/png",
+            "jpg" | "jpeg" => "image/jpeg",
+            "gif" => "image/gif",
+            "svg" => "image/svg+xml",
+            "ico" => "image/x-icon",
+            "webp" => "image/webp",
+            "avif" => "image/avif",
+            "bmp" => "image/bmp",
+            "woff" => "font/woff",
+            "woff2" => "font/woff2",
+            "ttf" => "font/ttf",
+            "otf" => "font/otf",
+            "eot" => "application/vnd.ms-fontobject",
+            "wasm" => "application/wasm",
+            "pdf" => "application/pdf",
+            "zip" => "application/zip",
+            "gz" => "application/gzip",
+            "mp3" => "audio/mpeg",
+            "ogg" => "audio/ogg",
+            "wav" => "audio/wav",
+            "mp4" => "video/mp4",
+            "webm" => "video/webm",
+            "webmanifest" => "application/manifest+json",
+            _ => return None
+        };
+
+        Some(mime)
+    }
+
+    /// MIME type by the extension of the last path segment, DEFAULT if unknown
+    pub fn from_path(path: &str) -> &'static str {
+        let name = path.rsplit('/').next().unwrap_or(path);
+
+        match name.rfind('.') {
+            Some(idx) => from_extension(&name[idx + 1..]).unwrap_or(DEFAULT),
+            None => DEFAULT
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_from_path() {
+            assert_eq!(from_path("/bundle.js"), "text/javascript; charset=utf-8");
+            assert_eq!(from_path("dist/index.HTML"), "text/html; charset=utf-8");
+            assert_eq!(from_path("module.wasm"), "application/wasm");
+            assert_eq!(from_path("fonts/roboto.woff2"), "font/woff2");
+        }
+
+        #[test]
+        fn test_from_path_unknown() {
+            assert_eq!(from_path("archive.unknown"), DEFAULT);
+            assert_eq!(from_path("v1.0/README"), DEFAULT);
+        }
+    }
+}
+
+/// Server-sent events, one-way live updates over a text/event-stream response
+pub mod sse {
+    use std::io::{Error, ErrorKind};
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use super::single_line;
+
+    /// Comment sent while idle, so proxies keep the connection
+    /// open and a vanished client is noticed on write
+    pub const HEARTBEAT: Duration = Duration::from_secs(15);
+
+    /// Events queued for the stream before senders block
+    pub const QUEUE_SIZE: usize = 256;
+
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct Event {
+        event: Option<String>,
+        id: Option<String>,
+        data: String,
+        retry: Option<Duration>
+    }
+
+    impl Event {
+        /// Event with data, split over data fields by line
+        pub fn new(data: impl Into<String>) -> Event {
+            Event { data: data.into(), ..Event::default() }
+        }
+
+        /// Event type, "message" when not set
+        pub fn event(mut self, event: &str) -> Event {
+            self.event = Some(single_line(event));
+            self
+        }
+
+        /// Sent back by the client as Last-Event-ID when it reconnects
+        pub fn id(mut self, id: &str) -> Event {
+            self.id = Some(single_line(id));
+            self
+        }
+
+        /// How long the client waits before reconnecting
+        pub fn retry(mut self, retry: Duration) -> Event {
+            self.retry = Some(retry);
+            self
+        }
+
+        pub fn to_bytes(&self) -> Vec<u8> {
+            let mut fields = vec![];
+
+            if let Some(event) = &self.event {
+                fields.push(format!("event: {}", event));
+            }
+
+            if let Some(id) = &self.id {
+                fields.push(format!("id: {}", id));
+            }
+
+            if let Some(retry) = self.retry {
+                fields.push(format!("retry: {}", retry.as_millis()));
+            }
+
+            // Any of CRLF, CR or LF ends a line of the stream
+            fields.extend(self.data.replace("\r\n", "\n")
+                          .split(['\r', '\n'])
+                          .map(|line| format!("data: {}", line)));
+
+            format!("{}\n\n", fields.join("\n")).into_bytes()
+        }
+    }
+
+    /// Handle for pushing events to a stream from any thread,
+    /// sending fails once the client is gone
+    #[derive(Clone)]
+    pub struct Sender {
+        queue: mpsc::SyncSender<Event>
+    }
+
+    impl Sender {
+        /// Blocks while the queue is full
+        pub fn send(&self, event: Event) -> Result<(), Error> {
+            self.queue.send(event).map_err(|_| ended())
+        }
+
+        /// Fails with WouldBlock instead of waiting while the queue is full
+        pub fn try_send(&self, event: Event) -> Result<(), Error> {
+            match self.queue.try_send(event) {
+                Ok(_) => Ok(()),
+                Err(mpsc::TrySendError::Full(_)) => Err(Error::new(ErrorKind::WouldBlock, "Queue is full")),
+                Err(mpsc::TrySendError::Disconnected(_)) => Err(ended())
+            }
+        }
+
+        pub fn data(&self, data: impl Into<String>) -> Result<(), Error> {
+            self.send(Event::new(data))
+        }
+    }
+
+    fn ended() -> Error {
+        Error::new(ErrorKind::BrokenPipe, "Event stream has ended")
+    }
+
+    /// Body of an event stream, see `response::sse`. Ends once every sender is dropped
+    pub struct Stream {
+        events: mpsc::Receiver<Event>,
+        heartbeat: Duration
+    }
+
+    impl Iterator for Stream {
+        type Item = Vec<u8>;
+
+        fn next(&mut self) -> Option<Vec<u8>> {
+            match self.events.recv_timeout(self.heartbeat) {
+                Ok(event) => Some(event.to_bytes()),
+                Err(mpsc::RecvTimeoutError::Timeout) => Some(b": heartbeat\n\n".to_vec()),
+                Err(mpsc::RecvTimeoutError::Disconnected) => None
+            }
+        }
+    }
+
+    /// Event stream with a heartbeat comment sent after being idle for so long
+    pub fn channel(heartbeat: Duration) -> (Sender, Stream) {
+        let (queue, events) = mpsc::sync_channel(QUEUE_SIZE);
+
+        (Sender { queue }, Stream { events, heartbeat })
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_event_to_bytes() {
+            let event = Event::new("first\nsecond")
+                .event("update")
+                .id("7\n")
+                .retry(Duration::from_secs(3));
+
+            assert_eq!(String::from_utf8(event.to_bytes()).unwrap(),
+                       "event: update\nid: 7\nretry: 3000\ndata: first\ndata: second\n\n");
+            assert_eq!(Event::new("").to_bytes(), b"data: \n\n");
+        }
+
+        #[test]
+        fn test_event_data_line_breaks() {
+            assert_eq!(Event::new("x\rid: evil").to_bytes(), b"data: x\ndata: id: evil\n\n");
+            assert_eq!(Event::new("a\r\nb\nc\r\rd").to_bytes(),
+                       b"data: a\ndata: b\ndata: c\ndata: \ndata: d\n\n");
+        }
+
+        #[test]
+        fn test_stream() {
+            let (sender, mut stream) = channel(Duration::from_millis(10));
+
+            sender.data("hello").unwrap();
+
+            assert_eq!(stream.next().unwrap(), b"data: hello\n\n");
+            assert_eq!(stream.next().unwrap(), b": heartbeat\n\n");
+
+            drop(sender);
+            assert_eq!(stream.next(), None);
+        }
+
+        #[test]
+        fn test_send_after_stream_ended() {
+            let (sender, stream) = channel(HEARTBEAT);
+
+            drop(stream);
+
+            assert_eq!(sender.data("hello").unwrap_err().kind(), ErrorKind::BrokenPipe);
+        }
+    }
+}
+
+/// Line breaks would end a header or event field early
+fn single_line(value: &str) -> String {
+    value.chars().filter(|c| *c != '\r' && *c != '\n').collect()
+}
+
+pub mod response {
+    use std::borrow::Cow;
+    use std::fmt;
+    use std::io::{Write, Error};
+
+    use super::{mime, sse, single_line};
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct StatusCode(u16);
+
+    macro_rules! status_codes {
+        ($($name:ident = ($code:expr, $reason:expr);)+) => {
+            impl StatusCode {
+                $(pub const $name: StatusCode = StatusCode($code);)+
+
+                /// Reason phrase registered for the code, empty for unregistered codes
+                pub fn reason_phrase(&self) -> &'static str {
+                    match self.0 {
+                        $($code => $reason,)+
+                        _ => ""
+                    }
+                }
+            }
+        }
     }
 
-    pub struct Frame {
-        pub payload: Vec<u8>
+    // * HTTP STATUS CODES *
+    // As registered in the IANA HTTP Status Code Registry
+    status_codes! {
+        CONTINUE = (100, "Continue");
+        SWITCHING_PROTOCOLS = (101, "Switching Protocols");
+        PROCESSING = (102, "Processing");
+        EARLY_HINTS = (103, "Early Hints");
+        OK = (200, "OK");
+        CREATED = (201, "Created");
+        ACCEPTED = (202, "Accepted");
+        NON_AUTHORITATIVE_INFORMATION = (203, "Non-Authoritative Information");
+        NO_CONTENT = (204, "No Content");
+        RESET_CONTENT = (205, "Reset Content");
+        PARTIAL_CONTENT = (206, "Partial Content");
+        MULTI_STATUS = (207, "Multi-Status");
+        ALREADY_REPORTED = (208, "Already Reported");
+        IM_USED = (226, "IM Used");
+        MULTIPLE_CHOICES = (300, "Multiple Choices");
+        MOVED_PERMANENTLY = (301, "Moved Permanently");
+        FOUND = (302, "Found");
+        SEE_OTHER = (303, "See Other");
+        NOT_MODIFIED = (304, "Not Modified");
+        USE_PROXY = (305, "Use Proxy");
+        TEMPORARY_REDIRECT = (307, "Temporary Redirect");
+        PERMANENT_REDIRECT = (308, "Permanent Redirect");
+        BAD_REQUEST = (400, "Bad Request");
+        UNAUTHORIZED = (401, "Unauthorized");
+        PAYMENT_REQUIRED = (402, "Payment Required");
+        FORBIDDEN = (403, "Forbidden");
+        NOT_FOUND = (404, "Not Found");
+        METHOD_NOT_ALLOWED = (405, "Method Not Allowed");
+        NOT_ACCEPTABLE = (406, "Not Acceptable");
+        PROXY_AUTHENTICATION_REQUIRED = (407, "Proxy Authentication Required");
+        REQUEST_TIMEOUT = (408, "Request Timeout");
+        CONFLICT = (409, "Conflict");
+        GONE = (410, "Gone");
+        LENGTH_REQUIRED = (411, "Length Required");
+        PRECONDITION_FAILED = (412, "Precondition Failed");
+        PAYLOAD_TOO_LARGE = (413, "Payload Too Large");
+        URI_TOO_LONG = (414, "URI Too Long");
+        UNSUPPORTED_MEDIA_TYPE = (415, "Unsupported Media Type");
+        RANGE_NOT_SATISFIABLE = (416, "Range Not Satisfiable");
+        EXPECTATION_FAILED = (417, "Expectation Failed");
+        IM_A_TEAPOT = (418, "I'm a teapot");
+        MISDIRECTED_REQUEST = (421, "Misdirected Request");
+        UNPROCESSABLE_ENTITY = (422, "Unprocessable Entity");
+        LOCKED = (423, "Locked");
+        FAILED_DEPENDENCY = (424, "Failed Dependency");
+        TOO_EARLY = (425, "Too Early");
+        UPGRADE_REQUIRED = (426, "Upgrade Required");
+        PRECONDITION_REQUIRED = (428, "Precondition Required");
+        TOO_MANY_REQUESTS = (429, "Too Many Requests");
+        REQUEST_HEADER_FIELDS_TOO_LARGE = (431, "Request Header Fields Too Large");
+        UNAVAILABLE_FOR_LEGAL_REASONS = (451, "Unavailable For Legal Reasons");
+        INTERNAL_SERVER_ERROR = (500, "Internal Server Error");
+        NOT_IMPLEMENTED = (501, "Not Implemented");
+        BAD_GATEWAY = (502, "Bad Gateway");
+        SERVICE_UNAVAILABLE = (503, "Service Unavailable");
+        GATEWAY_TIMEOUT = (504, "Gateway Timeout");
+        HTTP_VERSION_NOT_SUPPORTED = (505, "HTTP Version Not Supported");
+        VARIANT_ALSO_NEGOTIATES = (506, "Variant Also Negotiates");
+        INSUFFICIENT_STORAGE = (507, "Insufficient Storage");
+        LOOP_DETECTED = (508, "Loop Detected");
+        NOT_EXTENDED = (510, "Not Extended");
+        NETWORK_AUTHENTICATION_REQUIRED = (511, "Network Authentication Required");
     }
 
-    impl Frame {
-        pub fn new(mut msg: Vec<u8>, opcode: Opcode) -> Frame {
-            let payload = match msg.len() {
-                length if length < 126 => {
-                    let mut header =  vec![128 + opcode as u8, length as u8];
-                    header.append(&mut msg);
+    impl StatusCode {
+        /// Status codes are three digit integers from 100 to 599
+        pub fn from_u16(code: u16) -> Option<StatusCode> {
+            match code {
+                100..=599 => Some(StatusCode(code)),
+                _ => None
+            }
+        }
 
-                    header
-                },
-                length if length < 65536 => {
-                    let mut header = vec![128 + opcode as u8, 126];
-                    header.append(&mut (msg.len() as u16).to_be_bytes().to_vec());
-                    header.append(&mut msg);
+        pub fn code(&self) -> u16 {
+            self.0
+        }
 
-                    header
-                },
-                // TODO: support for payload length == 127 (payload length >= 2^16)
-                _ => panic!()
-            };
+        /// 1xx, 204 and 304 responses never carry a message body
+        pub fn allows_body(&self) -> bool {
+            !matches!(self.0, 100..=199 | 204 | 304)
+        }
+    }
 
-            Frame {
-                payload: payload
-            }
+    impl fmt::Display for StatusCode {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{} {}", self.0, self.reason_phrase())
         }
     }
 
-    #[derive(Debug)]
-    pub struct Header {
-        is_final_frame: bool,
-        pub opcode: Opcode,
-        pub is_masked: bool,
-        pub payload_length: usize
+    /// Chunks of a streamed body, written to the client as soon as they are produced
+    pub type Chunks = Box<dyn Iterator<Item = Vec<u8>> + Send>;
+
+    /// Body is kept as bytes so any binary content can be served,
+    /// and embedded assets are borrowed instead of copied per response
+    pub enum Body {
+        Full(Cow<'static, [u8]>),
+        Chunked(Chunks)
     }
 
-    impl Header {
-        pub fn new(is_final_frame: bool,
-                   opcode: Opcode,
-                   is_masked: bool,
-                   payload_length: usize) -> Header {
-            Header {
-                is_final_frame: is_final_frame,
-                opcode: opcode,
-                is_masked: is_masked,
-                payload_length: payload_length
-            }
+    impl From<Vec<u8>> for Body {
+        fn from(body: Vec<u8>) -> Body {
+            Body::Full(Cow::Owned(body))
         }
     }
 
-    pub fn unmask_payload(payload: Vec<u8>, masking_key: Option<[u8; 4]>) -> Vec<u8> {
-        match masking_key {
-            Some(key) => payload.iter()
-                    .enumerate()
-                    .map(|(i, val)| val ^ key[i % 4])
-                    .collect(),
-            None => payload
+    impl From<&'static [u8]> for Body {
+        fn from(body: &'static [u8]) -> Body {
+            Body::Full(Cow::Borrowed(body))
+        }
+    }
+
+    impl<const N: usize> From<&'static [u8; N]> for Body {
+        fn from(body: &'static [u8; N]) -> Body {
+            Body::Full(Cow::Borrowed(body))
+        }
+    }
+
+    impl From<String> for Body {
+        fn from(body: String) -> Body {
+            Body::from(body.into_bytes())
+        }
+    }
+
+    /// Copied, so the body may borrow from anything, e.g. a local String
+    impl From<&str> for Body {
+        fn from(body: &str) -> Body {
+            Body::from(body.as_bytes().to_vec())
+        }
+    }
+
+    impl From<&String> for Body {
+        fn from(body: &String) -> Body {
+            Body::from(body.as_str())
         }
     }
-}
 
-pub mod response {
     pub struct Response {
-        headers: Vec<u8>,
-        body: Vec<u8>
+        status: StatusCode,
+        headers: Vec<(String, String)>,
+        body: Body
     }
 
     impl Response {
-        pub fn to_bytes(&self) -> Vec<u8> {
-            [&self.headers[..], &self.body[..]].concat()
+        pub fn new(status: StatusCode) -> Response {
+            Response {
+                status,
+                headers: vec![],
+                body: Body::Full(Cow::Borrowed(&[]))
+            }
         }
-    }
 
-    pub fn ok(msg: &str, mut headers: Vec<String>) -> Response {
-        let body = msg.as_bytes();
+        pub fn status(&self) -> StatusCode {
+            self.status
+        }
+
+        pub fn headers(&self) -> &[(String, String)] {
+            &self.headers
+        }
+
+        /// Set header, replacing any previous value with the same case-insensitive name
+        pub fn header(mut self, name: &str, value: &str) -> Response {
+            self.headers.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+
+            self.append_header(name, value)
+        }
+
+        /// Add header without replacing previous values, e.g. for multiple Set-Cookie headers.
+        /// Line breaks are stripped so a value can't inject headers or split the response,
+        /// and Content-Length and Transfer-Encoding are left to be derived from the body
+        pub fn append_header(mut self, name: &str, value: &str) -> Response {
+            let name = single_line(name);
+
+            if !name.eq_ignore_ascii_case("content-length") && !name.eq_ignore_ascii_case("transfer-encoding") {
+                self.headers.push((name, single_line(value)));
+            }
+
+            self
+        }
+
+        pub fn body(mut self, body: impl Into<Body>) -> Response {
+            self.body = body.into();
+
+            self
+        }
+
+        /// Stream body with chunked transfer coding, for payloads
+        /// whose length is not known up front
+        pub fn chunked(mut self, chunks: impl Iterator<Item = Vec<u8>> + Send + 'static) -> Response {
+            self.body = Body::Chunked(Box::new(chunks));
+
+            self
+        }
+
+        /// Write response to writer. Chunked body is flushed chunk by chunk
+        /// and terminated by the last-chunk once the iterator is exhausted
+        pub fn write_to<W: Write>(self, writer: &mut W) -> Result<(), Error> {
+            writer.write_all(&self.head())?;
+
+            match self.body {
+                Body::Full(body) => writer.write_all(&body)?,
+                Body::Chunked(chunks) => {
+                    // empty chunk would be read as the last-chunk, so skip those
+                    for chunk in chunks.filter(|chunk| !chunk.is_empty()) {
+                        writer.write_all(format!("{:X}\r\n", chunk.len()).as_bytes())?;
+                        writer.write_all(&chunk)?;
+                        writer.write_all(b"\r\n")?;
+                        writer.flush()?;
+                    }
+
+                    writer.write_all(b"0\r\n\r\n")?;
+                }
+            }
 
-        headers.insert(0, "HTTP/1.1 200 OK".to_string());
-        headers.push(format!("Content-Length: {}\r\n\r\n", body.len()));
+            writer.flush()
+        }
+
+        /// Status line and headers only, i.e. the answer to a HEAD request.
+        /// Content-Length still tells the length of the body left out
+        pub fn write_head_to<W: Write>(self, writer: &mut W) -> Result<(), Error> {
+            writer.write_all(&self.head())?;
+            writer.flush()
+        }
+
+        /// Status line and headers, with the framing header derived from the body
+        fn head(&self) -> Vec<u8> {
+            let mut head = vec![format!("HTTP/1.1 {}", self.status)];
 
-        Response {
-            headers: headers.join("\r\n").as_bytes().to_vec(),
-            body: body.to_vec()
+            head.extend(self.headers.iter().map(|(name, value)| format!("{}: {}", name, value)));
+
+            match &self.body {
+                Body::Full(body) if self.status.allows_body() =>
+                    head.push(format!("Content-Length: {}", body.len())),
+                Body::Full(_) => {},
+                Body::Chunked(_) => head.push("Transfer-Encoding: chunked".to_string())
+            }
+
+            format!("{}\r\n\r\n", head.join("\r\n")).into_bytes()
         }
     }
 
-    pub fn websocket(key: String, proto: String) -> Response {
-        let headers = vec!["HTTP/1.1 101 Web Socket Protocol Handshake".to_string(),
-                           "Connection: Upgrade".to_string(),
-                           "Content-Length: 0".to_string(),
-                           format!("Sec-WebSocket-Accept: {}", key),
-                           format!("Sec-WebSocket-Protocol: {}", proto),
-                           "Upgrade: websocket\r\n\r\n".to_string()];
+    pub fn ok(body: impl Into<Body>) -> Response {
+        Response::new(StatusCode::OK).body(body)
+    }
+
+    /// Static asset with Content-Type looked up from the extension of its name,
+    /// e.g. `asset("logo.png", include_bytes!("logo.png"))`
+    pub fn asset(name: &str, body: impl Into<Body>) -> Response {
+        ok(body).header("Content-Type", mime::from_path(name))
+    }
+
+    pub fn chunked(chunks: impl Iterator<Item = Vec<u8>> + Send + 'static) -> Response {
+        Response::new(StatusCode::OK).chunked(chunks)
+    }
+
+    /// Event stream kept open until every sender of events is dropped
+    /// or the client goes away, see `sse::channel`
+    pub fn sse(events: sse::Stream) -> Response {
+        chunked(events)
+            .header("Content-Type", "text/event-stream")
+            .header("Cache-Control", "no-cache")
+    }
+
+    /// Plain text response with the status line as body
+    pub fn error(status: StatusCode) -> Response {
+        Response::new(status)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(status.to_string())
+    }
+
+    pub fn bad_request() -> Response {
+        error(StatusCode::BAD_REQUEST)
+    }
+
+    pub fn not_found() -> Response {
+        error(StatusCode::NOT_FOUND)
+    }
+
+    pub fn internal_error() -> Response {
+        error(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    /// Temporary redirect to location
+    pub fn redirect(location: &str) -> Response {
+        Response::new(StatusCode::FOUND)
+            .header("Location", location)
+    }
+
+    /// Accept websocket upgrade, with Sec-WebSocket-Protocol
+    /// only when the client offered a subprotocol that was selected
+    pub fn websocket(key: String, protocol: Option<&str>) -> Response {
+        let response = Response::new(StatusCode::SWITCHING_PROTOCOLS)
+            .header("Connection", "Upgrade")
+            .header("Sec-WebSocket-Accept", &key);
+
+        match protocol {
+            Some(protocol) => response.header("Sec-WebSocket-Protocol", protocol),
+            None => response
+        }.header("Upgrade", "websocket")
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn to_string(response: Response) -> String {
+            let mut written = vec![];
+            response.write_to(&mut written).unwrap();
+
+            String::from_utf8(written).unwrap()
+        }
+
+        #[test]
+        fn test_websocket_protocol_header() {
+            let with = to_string(websocket("key".to_string(), Some("chat")));
+            let without = to_string(websocket("key".to_string(), None));
+
+            assert!(with.contains("Sec-WebSocket-Protocol: chat\r\n"));
+            assert!(!without.contains("Sec-WebSocket-Protocol"));
+        }
+
+        #[test]
+        fn test_ok_write_to() {
+            let response = ok("hello").header("Content-Type", "text/plain");
+
+            assert_eq!(to_string(response),
+                       "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\nhello")
+        }
+
+        #[test]
+        fn test_chunked_write_to() {
+            let chunks = vec![b"Wiki".to_vec(), vec![], b"pedia in chunks".to_vec()];
+
+            assert_eq!(to_string(chunked(chunks.into_iter())),
+                       "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                        4\r\nWiki\r\nF\r\npedia in chunks\r\n0\r\n\r\n")
+        }
+
+        #[test]
+        fn test_sse_write_to() {
+            let (sender, events) = sse::channel(sse::HEARTBEAT);
+
+            sender.send(sse::Event::new("hi").id("1")).unwrap();
+            drop(sender);
+
+            assert_eq!(to_string(sse(events)),
+                       "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+                        Transfer-Encoding: chunked\r\n\r\n10\r\nid: 1\ndata: hi\n\n\r\n0\r\n\r\n")
+        }
+
+        #[test]
+        fn test_header_injection() {
+            let response = redirect("/next\r\nSet-Cookie: session=evil\r\n\r\n<html>")
+                .header("X-Name\n", "a\rb");
+
+            assert_eq!(response.headers(), &[("Location".to_string(), "/nextSet-Cookie: session=evil<html>".to_string()),
+                                             ("X-Name".to_string(), "ab".to_string())]);
+        }
+
+        #[test]
+        fn test_framing_headers_are_derived() {
+            let response = ok("hello")
+                .header("Content-Length", "100")
+                .header("transfer-encoding", "chunked");
+
+            assert_eq!(to_string(response), "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello")
+        }
+
+        #[test]
+        fn test_borrowed_str_body() {
+            let name = format!("{}-{}", "local", 1);
+
+            assert!(to_string(ok(&name)).ends_with("\r\n\r\nlocal-1"));
+            assert!(to_string(ok(name.as_str())).ends_with("\r\n\r\nlocal-1"));
+        }
+
+        #[test]
+        fn test_write_head_to() {
+            let mut written = vec![];
+            ok("hello").write_head_to(&mut written).unwrap();
+
+            assert_eq!(written, b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n")
+        }
+
+        #[test]
+        fn test_binary_body() {
+            let mut written = vec![];
+            ok(vec![0, 255, 1]).write_to(&mut written).unwrap();
+
+            assert!(written.ends_with(b"Content-Length: 3\r\n\r\n\x00\xff\x01"))
+        }
+
+        #[test]
+        fn test_asset() {
+            let response = asset("favicon.ico", &[0, 0, 1, 0]);
+
+            assert_eq!(response.headers(),
+                       &[("Content-Type".to_string(), "image/x-icon".to_string())])
+        }
+
+        #[test]
+        fn test_not_found() {
+            assert_eq!(to_string(not_found()),
+                       "HTTP/1.1 404 Not Found\r\nContent-Type: text/plain; charset=utf-8\r\n\
+                        Content-Length: 13\r\n\r\n404 Not Found")
+        }
+
+        #[test]
+        fn test_redirect() {
+            assert_eq!(to_string(redirect("/login")),
+                       "HTTP/1.1 302 Found\r\nLocation: /login\r\nContent-Length: 0\r\n\r\n")
+        }
+
+        #[test]
+        fn test_no_content_length_without_body() {
+            let response = Response::new(StatusCode::NO_CONTENT);
+
+            assert_eq!(to_string(response), "HTTP/1.1 204 No Content\r\n\r\n")
+        }
+
+        #[test]
+        fn test_header_replaces() {
+            let response = ok("")
+                .header("Content-Type", "text/plain")
+                .header("content-type", "text/html")
+                .append_header("Set-Cookie", "a=1")
+                .append_header("Set-Cookie", "b=2");
+
+            assert_eq!(response.headers(),
+                       &[("content-type".to_string(), "text/html".to_string()),
+                         ("Set-Cookie".to_string(), "a=1".to_string()),
+                         ("Set-Cookie".to_string(), "b=2".to_string())])
+        }
 
-        Response {
-            headers: headers.join("\r\n").as_bytes().to_vec(),
-            body: vec![]
+        #[test]
+        fn test_status_code() {
+            assert_eq!(StatusCode::from_u16(418), Some(StatusCode::IM_A_TEAPOT));
+            assert_eq!(StatusCode::from_u16(499).unwrap().reason_phrase(), "");
+            assert_eq!(StatusCode::from_u16(99), None);
+            assert_eq!(StatusCode::SERVICE_UNAVAILABLE.to_string(), "503 Service Unavailable")
         }
     }
 }
@@ -129,31 +1346,64 @@ pub mod request {
     extern crate base64;
     extern crate crypto;
 
+    use std::any::{Any, TypeId};
     use std::collections::HashMap;
+    use std::fmt;
+    use std::sync::Arc;
     use crypto::digest::Digest;
     use crypto::sha1::Sha1;
     use base64::encode;
 
     const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 
-    #[derive(Debug)]
+    // * HTTP METHODS *
+    // GET, HEAD, POST, PUT, DELETE, CONNECT, OPTIONS and TRACE are defined in RFC 7231,
+    // PATCH in RFC 5789. Any other token is kept as an extension method.
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
     pub enum Method {
         GET,
-        // HEAD,
-        // POST,
-        // PUT,
-        // DELETE,
-        // CORRECT,
-        // OPTIONS,
-        // TRACE,
-        // PATCH
+        HEAD,
+        POST,
+        PUT,
+        DELETE,
+        CONNECT,
+        OPTIONS,
+        TRACE,
+        PATCH,
+        Extension(String)
+    }
+
+    impl Method {
+        pub fn as_str(&self) -> &str {
+            match self {
+                Method::GET => "GET",
+                Method::HEAD => "HEAD",
+                Method::POST => "POST",
+                Method::PUT => "PUT",
+                Method::DELETE => "DELETE",
+                Method::CONNECT => "CONNECT",
+                Method::OPTIONS => "OPTIONS",
+                Method::TRACE => "TRACE",
+                Method::PATCH => "PATCH",
+                Method::Extension(method) => method
+            }
+        }
+    }
+
+    impl fmt::Display for Method {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str(self.as_str())
+        }
     }
 
     #[derive(Debug)]
     pub struct Request {
         request: RequestLine,
         headers: HashMap<String, String>,
-        data: Option<String>
+        body: Option<Vec<u8>>,
+        trailers: HashMap<String, String>,
+        params: HashMap<String, String>,
+        states: HashMap<TypeId, Arc<dyn Any + Send + Sync>>
     }
 
     #[derive(Debug)]
@@ -166,61 +1416,148 @@ pub mod request {
     impl Request {
         pub fn new(request: RequestLine,
                    headers: HashMap<String, String>,
-                   data: Option<String>) -> Request {
+                   body: Option<Vec<u8>>) -> Request {
             Request {
-                request: request,
-                headers: headers,
-                data: data
+                request,
+                headers,
+                body,
+                trailers: HashMap::new(),
+                params: HashMap::new(),
+                states: HashMap::new()
             }
         }
 
+        /// Trailer fields sent after a chunked body, kept apart from the headers
+        pub fn with_trailers(mut self, trailers: HashMap<String, String>) -> Request {
+            self.trailers = trailers;
+            self
+        }
+
         pub fn get_method_and_uri(&self) -> (&Method, &str) {
             self.request.get_method_and_uri()
         }
 
+        /// Request target without the query string
+        pub fn path(&self) -> &str {
+            let uri = &self.request.uri;
+
+            uri.split('?').next().unwrap_or(uri)
+        }
+
+        /// Query string without the leading '?'
+        pub fn query(&self) -> Option<&str> {
+            self.request.uri.split_once('?').map(|(_, query)| query)
+        }
+
+        /// Percent-decoded path parameter captured by the router, e.g. `id` of `/users/:id`
+        pub fn param(&self, name: &str) -> Option<&str> {
+            self.params.get(name).map(|param| param.as_ref())
+        }
+
+        pub fn params(&self) -> &HashMap<String, String> {
+            &self.params
+        }
+
+        pub(crate) fn set_params(&mut self, params: HashMap<String, String>) {
+            self.params = params;
+        }
+
+        /// Application state of type T shared by the server, see `web::server::with_state`
+        pub fn state<T: Any + Send + Sync>(&self) -> Option<Arc<T>> {
+            self.states.get(&TypeId::of::<T>())
+                .and_then(|state| Arc::clone(state).downcast().ok())
+        }
+
+        pub(crate) fn set_state<T: Any + Send + Sync>(&mut self, state: Arc<T>) {
+            self.states.insert(TypeId::of::<T>(), state);
+        }
+
+        pub fn version(&self) -> &str {
+            &self.request.version
+        }
+
         pub fn headers(&self) -> &HashMap<String, String> {
             &self.headers
         }
 
+        /// Raw message body, present when the request had a Content-Length or a chunked body
+        pub fn body(&self) -> Option<&[u8]> {
+            self.body.as_deref()
+        }
+
+        /// Trailer fields of a chunked body, names in lowercase like headers
+        pub fn trailers(&self) -> &HashMap<String, String> {
+            &self.trailers
+        }
+
+        /// HTTP/1.1 connections persist unless the client asks to close them,
+        /// HTTP/1.0 connections only when the client asks to keep them alive
+        pub fn is_keep_alive(&self) -> bool {
+            let has_option = |option: &str| match self.headers.get("connection") {
+                Some(con) => con.split(',').any(|opt| opt.trim().eq_ignore_ascii_case(option)),
+                None => false
+            };
+
+            match self.version() {
+                "HTTP/1.1" => !has_option("close"),
+                _ => has_option("keep-alive")
+            }
+        }
+
+        /// Connection is a list of options, e.g. "keep-alive, Upgrade" from Firefox
         pub fn is_websocket_upgrade(&self) -> bool {
             match (self.headers.get("connection"), self.headers.get("upgrade")) {
                 (Some(con), Some(upg)) =>
-                    con.to_lowercase() == "upgrade" && upg.to_lowercase() == "websocket",
+                    con.split(',').any(|option| option.trim().eq_ignore_ascii_case("upgrade"))
+                        && upg.trim().eq_ignore_ascii_case("websocket"),
                 (_, _) => false
             }
         }
 
+        /// Subprotocols offered by the client, in its order of preference
         pub fn get_websocket_protocol(&self) -> Option<Vec<&str>> {
-            match self.headers.get("sec-websocket-protocol") {
-                Some(protos) => {
-                    Some(protos.split(",").collect())
-                },
-                None => None
-            }
+            self.headers.get("sec-websocket-protocol")
+                .map(|protos| protos.split(',')
+                     .map(|proto| proto.trim())
+                     .filter(|proto| !proto.is_empty())
+                     .collect())
         }
 
-        pub fn generate_websocket_accept_value(&self) -> Option<String> {
-            match self.headers.get("sec-websocket-key") {
-                Some(val) => {
-                    let mut hasher = Sha1::new();
-                    hasher.input_str(&format!("{}{}", val, WEBSOCKET_GUID).to_string());
+        /// First subprotocol offered by the client that is also supported,
+        /// None when the client didn't offer any of them
+        pub fn select_websocket_protocol<'a>(&self, supported: &[&'a str]) -> Option<&'a str> {
+            self.get_websocket_protocol()?
+                .into_iter()
+                .find_map(|offered| supported.iter().find(|proto| **proto == offered).copied())
+        }
 
-                    let mut hash = vec![0; hasher.output_bytes()];
-                    hasher.result(&mut hash);
+        /// Id of the last server-sent event the client saw, sent when it reconnects
+        pub fn last_event_id(&self) -> Option<&str> {
+            self.headers.get("last-event-id").map(|id| id.trim())
+        }
 
-                    Some(encode(&hash))
-                },
-                None => None
-            }
+        pub fn generate_websocket_accept_value(&self) -> Option<String> {
+            self.headers.get("sec-websocket-key").map(|key| websocket_accept_value(key))
         }
     }
 
+    /// Sec-WebSocket-Accept answering Sec-WebSocket-Key, also for checking the answer as a client
+    pub fn websocket_accept_value(key: &str) -> String {
+        let mut hasher = Sha1::new();
+        hasher.input_str(&format!("{}{}", key, WEBSOCKET_GUID));
+
+        let mut hash = vec![0; hasher.output_bytes()];
+        hasher.result(&mut hash);
+
+        encode(&hash)
+    }
+
     impl RequestLine {
         pub fn new(method: Method, uri: String, version: String) -> RequestLine {
             RequestLine {
-                method: method,
-                uri: uri,
-                version: version
+                method,
+                uri,
+                version
             }
         }
 
@@ -281,6 +1618,96 @@ pub mod request {
                        "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
         }
 
+        #[test]
+        fn test_is_websocket_upgrade() {
+            let upgrade = |connection: &str| {
+                let mut headers = HashMap::new();
+                headers.insert("connection".to_string(), connection.to_string());
+                headers.insert("upgrade".to_string(), "WebSocket".to_string());
+
+                Request::new(RequestLine::new(Method::GET, "/".to_string(), "HTTP/1.1".to_string()),
+                             headers,
+                             None).is_websocket_upgrade()
+            };
+
+            assert!(upgrade("Upgrade"));
+            assert!(upgrade("keep-alive, Upgrade"));
+            assert!(!upgrade("keep-alive"));
+        }
+
+        fn with_protocols(protocols: &str) -> Request {
+            let mut headers = HashMap::new();
+            headers.insert("sec-websocket-protocol".to_string(), protocols.to_string());
+
+            Request::new(RequestLine::new(Method::GET, "/".to_string(), "HTTP/1.1".to_string()),
+                         headers,
+                         None)
+        }
+
+        #[test]
+        fn test_get_websocket_protocol_trims() {
+            assert_eq!(with_protocols("json, chat ,,v2").get_websocket_protocol().unwrap(),
+                       vec!["json", "chat", "v2"]);
+        }
+
+        #[test]
+        fn test_select_websocket_protocol() {
+            let request = with_protocols("v2.chat, chat, json");
+
+            assert_eq!(request.select_websocket_protocol(&["json", "chat"]), Some("chat"));
+            assert_eq!(request.select_websocket_protocol(&["xml"]), None);
+            assert_eq!(request.select_websocket_protocol(&[]), None);
+        }
+
+        #[test]
+        fn test_last_event_id() {
+            let mut headers = HashMap::new();
+            headers.insert("last-event-id".to_string(), " 42".to_string());
+
+            let request = Request::new(RequestLine::new(Method::GET, "/".to_string(), "HTTP/1.1".to_string()),
+                                       headers,
+                                       None);
+
+            assert_eq!(request.last_event_id(), Some("42"));
+            assert_eq!(with_protocols("json").last_event_id(), None);
+        }
+
+        fn with_connection(version: &str, connection: Option<&str>) -> Request {
+            let mut headers = HashMap::new();
+
+            if let Some(connection) = connection {
+                headers.insert("connection".to_string(), connection.to_string());
+            }
+
+            Request::new(RequestLine::new(Method::GET, "/".to_string(), version.to_string()),
+                         headers,
+                         None)
+        }
+
+        #[test]
+        fn test_is_keep_alive() {
+            assert!(with_connection("HTTP/1.1", None).is_keep_alive());
+            assert!(with_connection("HTTP/1.1", Some("keep-alive")).is_keep_alive());
+            assert!(!with_connection("HTTP/1.1", Some("Close")).is_keep_alive());
+            assert!(!with_connection("HTTP/1.0", None).is_keep_alive());
+            assert!(with_connection("HTTP/1.0", Some("Keep-Alive")).is_keep_alive());
+        }
+
+        #[test]
+        fn test_state() {
+            let mut request = Request::new(RequestLine::new(Method::GET,
+                                                            "/".to_string(),
+                                                            "".to_string()),
+                                           HashMap::new(),
+                                           None);
+            request.set_state(Arc::new(42usize));
+            request.set_state(Arc::new("config".to_string()));
+
+            assert_eq!(*request.state::<usize>().unwrap(), 42);
+            assert_eq!(*request.state::<String>().unwrap(), "config");
+            assert!(request.state::<u8>().is_none())
+        }
+
         #[test]
         fn test_generate_websocket_accept_value_bad() {
             let headers = HashMap::new();
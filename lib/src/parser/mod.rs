@@ -1,119 +1,1006 @@
 pub mod websocket {
     use std::net::TcpStream;
-    use std::io::{Read, BufReader, Error, ErrorKind};
+    use std::io::{Read, Write, Error, ErrorKind};
     use std::convert::TryInto;
+    use std::fmt;
 
-    use crate::http::websocket::{Opcode, Header, unmask_payload};
+    use crate::http::websocket::{Opcode, Header, Frame, Message, CloseCode, CloseFrame, masking_key, unmask_payload};
+    use crate::http::deflate::Inflater;
 
-    pub fn parse(stream: &TcpStream) -> Result<Option<Vec<u8>>, Error> {
-        let mut reader = BufReader::new(stream);
-        let mut header_buf = [0; 2];
+    /// Limit for a single message, fragmented or not, used by `parse`
+    pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
 
-        reader.by_ref().take(2).read(&mut header_buf).unwrap();
+    /// Why reading a message failed
+    #[derive(Debug)]
+    pub enum WebSocketError {
+        /// Connection ended in the middle of a frame
+        Truncated,
+        /// Opcode reserved by RFC 6455
+        BadOpcode(u8),
+        /// Client frames must be masked
+        Unmasked,
+        /// Message is over the size limit
+        TooBig(usize),
+        /// Text message isn't valid UTF-8
+        InvalidUtf8,
+        /// Frame is otherwise against the protocol, e.g. out of order
+        Protocol(&'static str),
+        Io(Error)
+    }
 
-        let header = parse_header(header_buf).unwrap();
-        let payload_length = get_actual_payload_length(&header, &mut reader).unwrap();
+    impl WebSocketError {
+        /// Status code to close the connection with, None when the connection is gone anyway
+        pub fn close_code(&self) -> Option<CloseCode> {
+            match self {
+                WebSocketError::Truncated | WebSocketError::Io(_) => None,
+                WebSocketError::BadOpcode(_)
+                    | WebSocketError::Unmasked
+                    | WebSocketError::Protocol(_) => Some(CloseCode::PROTOCOL_ERROR),
+                WebSocketError::TooBig(_) => Some(CloseCode::MESSAGE_TOO_BIG),
+                WebSocketError::InvalidUtf8 => Some(CloseCode::INVALID_PAYLOAD)
+            }
+        }
+    }
 
-        // TODO: payload could be in multiple frames...
-        match header.opcode {
-            Opcode::TEXT => {
-                let masking_key = get_masking_key(&header, &mut reader);
-                let mut payload = vec![0; payload_length];
+    impl fmt::Display for WebSocketError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                WebSocketError::Truncated => write!(f, "Truncated frame"),
+                WebSocketError::BadOpcode(opcode) => write!(f, "Bad opcode {}", opcode),
+                WebSocketError::Unmasked => write!(f, "Unmasked client frame"),
+                WebSocketError::TooBig(length) => write!(f, "Message too big: {} bytes", length),
+                WebSocketError::InvalidUtf8 => write!(f, "Invalid UTF-8 in text message"),
+                WebSocketError::Protocol(reason) => write!(f, "{}", reason),
+                WebSocketError::Io(err) => write!(f, "{}", err)
+            }
+        }
+    }
 
-                reader.read_exact(&mut payload).unwrap();
+    impl std::error::Error for WebSocketError {}
 
-                Ok(Some(unmask_payload(payload, masking_key)))
-            },
-            Opcode::CLOSE => Ok(None)
+    impl From<Error> for WebSocketError {
+        fn from(err: Error) -> WebSocketError {
+            match err.kind() {
+                ErrorKind::UnexpectedEof => WebSocketError::Truncated,
+                _ => WebSocketError::Io(err)
+            }
         }
     }
 
-    pub fn get_masking_key(header: &Header, reader: &mut BufReader<&TcpStream>)
-                           -> Option<[u8; 4]> {
+    impl From<WebSocketError> for Error {
+        fn from(err: WebSocketError) -> Error {
+            match err {
+                WebSocketError::Io(err) => err,
+                WebSocketError::Truncated => Error::new(ErrorKind::UnexpectedEof, err),
+                _ => Error::new(ErrorKind::InvalidData, err)
+            }
+        }
+    }
+
+    /// Read next data message with the default message size limit.
+    /// Frames are read straight from the stream, so nothing of the next
+    /// frame is left behind in a buffer between calls
+    pub fn parse(stream: &TcpStream) -> Result<Message, WebSocketError> {
+        parse_message(stream, DEFAULT_MAX_MESSAGE_SIZE)
+    }
+
+    /// Read client frames until a complete message arrives with a fresh `Parser`,
+    /// i.e. without any extensions
+    pub fn parse_message<S: Read + Write>(stream: S,
+                                          max_message_size: usize) -> Result<Message, WebSocketError> {
+        Parser::new(max_message_size).parse(stream)
+    }
+
+    /// Reads messages of one connection, keeping the state of negotiated extensions
+    pub struct Parser {
+        max_message_size: usize,
+        inflater: Option<Inflater>,
+        client: bool
+    }
+
+    impl Parser {
+        pub fn new(max_message_size: usize) -> Parser {
+            Parser {
+                max_message_size,
+                inflater: None,
+                client: false
+            }
+        }
+
+        /// Read frames of a server as a client: they are unmasked,
+        /// and pongs and close frames written in answer are masked
+        pub fn client(mut self) -> Parser {
+            self.client = true;
+
+            self
+        }
+
+        /// Refuse messages longer than max_message_size, keeping negotiated extensions
+        pub fn max_message_size(mut self, max_message_size: usize) -> Parser {
+            self.max_message_size = max_message_size;
+
+            self
+        }
+
+        /// Decompress messages marked with RSV1, permessage-deflate was negotiated
+        pub fn inflater(mut self, inflater: Inflater) -> Parser {
+            self.inflater = Some(inflater);
+
+            self
+        }
+
+        /// Read client frames until a complete message arrives, reassembling fragmented
+        /// messages. Pings are answered with a pong, also in between fragments, and
+        /// pongs are ignored. Close frame is echoed back to complete the closing
+        /// handshake, after which the connection should be closed.
+        /// On error the connection is failed with the matching close code
+        pub fn parse<S: Read + Write>(&mut self, mut stream: S) -> Result<Message, WebSocketError> {
+            let result = self.read_message(&mut stream);
+
+            // tell the other endpoint why the connection is failed,
+            // it is closed anyway so errors are ignored
+            if let Some(code) = result.as_ref().err().and_then(WebSocketError::close_code) {
+                stream.write_all(&self.outgoing(Frame::close(Some(&CloseFrame::new(code, "")))))
+                    .unwrap_or_default();
+            }
+
+            result
+        }
+
+        fn outgoing(&self, frame: Frame) -> Vec<u8> {
+            match self.client {
+                true => frame.masked(masking_key()).payload,
+                false => frame.payload
+            }
+        }
+
+        fn read_message<S: Read + Write>(&mut self, stream: &mut S) -> Result<Message, WebSocketError> {
+            let mut fragmented: Option<(Opcode, bool, Vec<u8>)> = None;
+            let extension_rsv = if self.inflater.is_some() { 0x4 } else { 0 };
+
+            loop {
+                let (header, masking_key) = parse_frame_header(stream)?;
+
+                validate(&header, extension_rsv, !self.client)?;
+
+                let buffered = fragmented.as_ref().map(|(_, _, data)| data.len()).unwrap_or(0);
+
+                if !header.opcode.is_control() && header.payload_length > self.max_message_size - buffered {
+                    return Err(WebSocketError::TooBig(buffered.saturating_add(header.payload_length)));
+                }
+
+                let mut payload = vec![0; header.payload_length];
+                stream.read_exact(&mut payload)?;
+                let mut payload = unmask_payload(payload, masking_key);
+
+                let (opcode, compressed, data) = match (header.opcode, fragmented.take()) {
+                    (Opcode::TEXT, None) | (Opcode::BINARY, None) => (header.opcode, header.rsv != 0, payload),
+                    (Opcode::CONTINUATION, Some((opcode, compressed, mut data))) => {
+                        data.append(&mut payload);
+
+                        (opcode, compressed, data)
+                    },
+                    (Opcode::CONTINUATION, None) =>
+                        return Err(WebSocketError::Protocol("Continuation without a message to continue")),
+                    (Opcode::TEXT, Some(_)) | (Opcode::BINARY, Some(_)) =>
+                        return Err(WebSocketError::Protocol("New message before previous was finished")),
+                    (Opcode::PING, unfinished) => {
+                        stream.write_all(&self.outgoing(Frame::new(payload, Opcode::PONG)))?;
+                        fragmented = unfinished;
+                        continue;
+                    },
+                    (Opcode::PONG, unfinished) => {
+                        fragmented = unfinished;
+                        continue;
+                    },
+                    (Opcode::CLOSE, _) => {
+                        validate_close(&payload)?;
+
+                        let close = CloseFrame::from_payload(&payload);
+                        stream.write_all(&self.outgoing(Frame::close(close.as_ref())))?;
+
+                        return Ok(Message::Close(close));
+                    }
+                };
+
+                if !header.is_final_frame {
+                    fragmented = Some((opcode, compressed, data));
+                    continue;
+                }
+
+                let data = match (compressed, self.inflater.as_mut()) {
+                    (true, Some(inflater)) => inflater.decompress(&data, self.max_message_size)
+                        .map_err(|_| WebSocketError::Protocol("Invalid compressed message"))?,
+                    _ => data
+                };
+
+                if data.len() > self.max_message_size {
+                    return Err(WebSocketError::TooBig(data.len()));
+                }
+
+                return match opcode {
+                    Opcode::TEXT => String::from_utf8(data)
+                        .map(Message::Text)
+                        .map_err(|_| WebSocketError::InvalidUtf8),
+                    _ => Ok(Message::Binary(data))
+                };
+            }
+        }
+    }
+
+    /// Check frame header against RFC 6455 section 5: frames from clients are masked
+    /// and from servers not, reserved bits are used only as negotiated extensions
+    /// allow and control frames are short and never fragmented. Extensions may only
+    /// mark the first frame of a message, e.g. RSV1 (0x4) for permessage-deflate
+    pub fn validate(header: &Header, extension_rsv: u8, from_client: bool) -> Result<(), WebSocketError> {
+        match header {
+            Header { is_masked: false, .. } if from_client => Err(WebSocketError::Unmasked),
+            Header { is_masked: true, .. } if !from_client => Err(WebSocketError::Protocol("Masked server frame")),
+            Header { rsv, .. } if *rsv & !extension_rsv != 0 => Err(WebSocketError::Protocol("Reserved bits set")),
+            Header { rsv, opcode, .. } if *rsv != 0 && *opcode != Opcode::TEXT && *opcode != Opcode::BINARY =>
+                Err(WebSocketError::Protocol("Reserved bits set on other than first frame of a message")),
+            Header { opcode, is_final_frame: false, .. } if opcode.is_control() =>
+                Err(WebSocketError::Protocol("Fragmented control frame")),
+            Header { opcode, payload_length, .. } if opcode.is_control() && *payload_length > 125 =>
+                Err(WebSocketError::Protocol("Control frame payload over 125 bytes")),
+            _ => Ok(())
+        }
+    }
+
+    /// Close frame payload is empty or a valid status code followed by UTF-8 reason
+    pub fn validate_close(payload: &[u8]) -> Result<(), WebSocketError> {
+        match payload {
+            [] => Ok(()),
+            [_] => Err(WebSocketError::Protocol("Close frame payload of one byte")),
+            [high, low, reason @ ..] => {
+                if !CloseCode::from_u16(u16::from_be_bytes([*high, *low])).is_valid() {
+                    return Err(WebSocketError::Protocol("Invalid close code"));
+                }
+
+                std::str::from_utf8(reason)
+                    .map(|_| ())
+                    .map_err(|_| WebSocketError::InvalidUtf8)
+            }
+        }
+    }
+
+    /// Read single frame and return its header with unmasked payload. Frames
+    /// announcing a payload longer than max_length fail before any of it is read
+    pub fn parse_frame<R: Read>(reader: &mut R, max_length: usize)
+                                -> Result<(Header, Vec<u8>), WebSocketError> {
+        let (header, masking_key) = parse_frame_header(reader)?;
+
+        if header.payload_length > max_length {
+            return Err(WebSocketError::TooBig(header.payload_length));
+        }
+
+        let mut payload = vec![0; header.payload_length];
+
+        reader.read_exact(&mut payload)?;
+
+        Ok((header, unmask_payload(payload, masking_key)))
+    }
+
+    /// Read frame header up to the payload. Payload length of the returned header
+    /// is the actual length, read from the extended payload length when present
+    pub fn parse_frame_header<R: Read>(reader: &mut R)
+                                       -> Result<(Header, Option<[u8; 4]>), WebSocketError> {
+        let mut header_buf = [0; 2];
+
+        reader.read_exact(&mut header_buf)?;
+
+        let mut header = parse_header(header_buf)?;
+        header.payload_length = get_actual_payload_length(&header, reader)?;
+        let masking_key = get_masking_key(&header, reader)?;
+
+        Ok((header, masking_key))
+    }
+
+    pub fn get_masking_key<R: Read>(header: &Header, reader: &mut R)
+                                    -> Result<Option<[u8; 4]>, WebSocketError> {
         match header.is_masked {
             true => {
                 let mut masking_key = [0; 4];
-                reader.take(4).read(&mut masking_key).unwrap();
+                reader.read_exact(&mut masking_key)?;
 
-                Some(masking_key)
+                Ok(Some(masking_key))
             },
-            false => None
+            false => Ok(None)
         }
     }
 
-    pub fn get_actual_payload_length(header: &Header, reader: &mut BufReader<&TcpStream>)
-                                 -> Result<usize, Error> {
+    pub fn get_actual_payload_length<R: Read>(header: &Header, reader: &mut R)
+                                              -> Result<usize, WebSocketError> {
         match header.payload_length {
-            length if length <= 125 => Ok(length.into()),
-            length if length == 126 => {
+            length if length <= 125 => Ok(length),
+            126 => {
                 let mut payload_buf = [0; 2];
-                reader.take(2).read(&mut payload_buf).unwrap();
+                reader.read_exact(&mut payload_buf)?;
 
                 Ok(u16::from_be_bytes(payload_buf).into())
             },
-            length if length == 127 => {
+            127 => {
                 let mut payload_buf = [0; 8];
-                reader.take(8).read(&mut payload_buf).unwrap();
+                reader.read_exact(&mut payload_buf)?;
 
-                // TODO: support 64 bit payload length
-                Ok(u64::from_be_bytes(payload_buf).try_into().unwrap())
+                match u64::from_be_bytes(payload_buf) {
+                    length if length >> 63 == 1 =>
+                        Err(WebSocketError::Protocol("Most significant bit of payload length set")),
+                    length => length.try_into()
+                        .map_err(|_| WebSocketError::TooBig(usize::MAX))
+                }
             },
-            _ => Err(Error::new(ErrorKind::InvalidInput, "Invalid payload length"))
+            _ => Err(WebSocketError::Protocol("Invalid payload length"))
         }
     }
 
-    pub fn parse_header(header: [u8; 2]) -> Result<Header, Error> {
+    pub fn parse_header(header: [u8; 2]) -> Result<Header, WebSocketError> {
         let is_final_frame = (header[0] >> 7) == 1;
+        let rsv = (header[0] >> 4) & 0x7;
         let opcode = header[0] & 0xF;
         let is_masked = (header[1] >> 7) == 1;
         let payload_length = header[1] & 0x7F;
 
-        match opcode {
-            1 => Ok(Header::new(is_final_frame,
-                                Opcode::TEXT,
-                                is_masked,
-                                payload_length as usize)),
-            8 => Ok(Header::new(is_final_frame,
-                                Opcode::CLOSE,
-                                is_masked,
-                                payload_length as usize)),
-            _ => Err(Error::new(ErrorKind::InvalidInput, "Bad opcode"))
+        match Opcode::from_u8(opcode) {
+            Some(opcode) => Ok(Header {
+                rsv,
+                ..Header::new(is_final_frame, opcode, is_masked, payload_length as usize)
+            }),
+            None => Err(WebSocketError::BadOpcode(opcode))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::http::deflate::Config;
+
+        /// Frames to read in and frames written out by the parser
+        struct Duplex {
+            input: std::io::Cursor<Vec<u8>>,
+            output: Vec<u8>,
+            read_size: usize
+        }
+
+        impl Duplex {
+            /// Client frames, masked with a zero key so payloads read as is
+            fn new(frames: &[&[u8]]) -> Duplex {
+                let masked: Vec<Vec<u8>> = frames.iter()
+                    .map(|frame| [&[frame[0], frame[1] | 0x80][..], &[0; 4], &frame[2..]].concat())
+                    .collect();
+
+                Duplex::unmasked(&masked.iter().map(|frame| &frame[..]).collect::<Vec<_>>())
+            }
+
+            fn unmasked(frames: &[&[u8]]) -> Duplex {
+                Duplex {
+                    input: std::io::Cursor::new(frames.concat()),
+                    output: vec![],
+                    read_size: usize::MAX
+                }
+            }
+        }
+
+        impl Read for Duplex {
+            fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+                let length = buf.len().min(self.read_size);
+
+                self.input.read(&mut buf[..length])
+            }
+        }
+
+        impl Write for Duplex {
+            fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+                self.output.write(buf)
+            }
+
+            fn flush(&mut self) -> Result<(), Error> {
+                Ok(())
+            }
+        }
+
+        #[test]
+        fn test_parse_message_fragmented() {
+            let mut stream = Duplex::new(&[&[0x01, 0x03, b'H', b'e', b'l'],
+                                           &[0x00, 0x01, b'l'],
+                                           &[0x80, 0x01, b'o']]);
+
+            assert_eq!(parse_message(&mut stream, 1024).unwrap(),
+                       Message::Text("Hello".to_string()));
+        }
+
+        #[test]
+        fn test_parse_message_control_between_fragments() {
+            let mut stream = Duplex::new(&[&[0x02, 0x02, 1, 2],
+                                           &[0x89, 0x01, b'p'],
+                                           &[0x8A, 0x00],
+                                           &[0x80, 0x01, 3]]);
+
+            assert_eq!(parse_message(&mut stream, 1024).unwrap(), Message::Binary(vec![1, 2, 3]));
+            assert_eq!(stream.output, vec![0x8A, 0x01, b'p']);
+        }
+
+        #[test]
+        fn test_parse_message_close_is_echoed() {
+            let mut stream = Duplex::new(&[&[0x88, 0x04, 0x03, 0xE8, b'o', b'k']]);
+
+            assert_eq!(parse_message(&mut stream, 1024).unwrap(),
+                       Message::Close(Some(CloseFrame::new(CloseCode::NORMAL, "ok"))));
+            assert_eq!(stream.output, vec![0x88, 0x04, 0x03, 0xE8, b'o', b'k']);
+        }
+
+        #[test]
+        fn test_parse_message_close_without_status() {
+            let mut stream = Duplex::new(&[&[0x88, 0x00]]);
+
+            assert_eq!(parse_message(&mut stream, 1024).unwrap(), Message::Close(None));
+            assert_eq!(stream.output, vec![0x88, 0x00]);
+        }
+
+        #[test]
+        fn test_parse_message_too_big() {
+            let mut stream = Duplex::new(&[&[0x01, 0x03, b'a', b'b', b'c'],
+                                           &[0x80, 0x03, b'd', b'e', b'f']]);
+
+            assert!(matches!(parse_message(&mut stream, 5), Err(WebSocketError::TooBig(6))));
+            assert_eq!(stream.output, vec![0x88, 0x02, 0x03, 0xF1]);
+        }
+
+        #[test]
+        fn test_parse_message_unmasked() {
+            let mut stream = Duplex::unmasked(&[&[0x81, 0x01, b'a']]);
+
+            assert!(matches!(parse_message(&mut stream, 1024), Err(WebSocketError::Unmasked)));
+            assert_eq!(stream.output, vec![0x88, 0x02, 0x03, 0xEA]);
+        }
+
+        #[test]
+        fn test_parse_message_invalid_utf8() {
+            let mut stream = Duplex::new(&[&[0x81, 0x02, 0xC3, 0x28]]);
+
+            assert!(matches!(parse_message(&mut stream, 1024), Err(WebSocketError::InvalidUtf8)));
+            assert_eq!(stream.output, vec![0x88, 0x02, 0x03, 0xEF]);
+        }
+
+        fn assert_fails(frames: &[&[u8]], code: CloseCode) {
+            let mut stream = Duplex::new(frames);
+            let err = parse_message(&mut stream, 1024).unwrap_err();
+
+            assert_eq!(err.close_code(), Some(code));
+            assert_eq!(stream.output, Frame::close(Some(&CloseFrame::new(code, ""))).payload);
+        }
+
+        #[test]
+        fn test_parse_message_reserved_bits() {
+            // Autobahn 3.1 - 3.7
+            for rsv in 1..8 {
+                assert_fails(&[&[0x81 | rsv << 4, 0x01, b'a']], CloseCode::PROTOCOL_ERROR);
+            }
+        }
+
+        #[test]
+        fn test_parse_message_fragmented_control() {
+            // Autobahn 5.1 and 5.2
+            assert_fails(&[&[0x09, 0x01, b'a'], &[0x80, 0x01, b'b']], CloseCode::PROTOCOL_ERROR);
+            assert_fails(&[&[0x0A, 0x01, b'a'], &[0x80, 0x01, b'b']], CloseCode::PROTOCOL_ERROR);
+        }
+
+        #[test]
+        fn test_parse_message_long_control() {
+            // Autobahn 2.5, ping with 126 bytes of payload
+            let mut stream = Duplex::unmasked(&[&[0x89, 0xFE, 0x00, 0x7E, 0, 0, 0, 0], &[0; 126]]);
+
+            assert!(parse_message(&mut stream, 1024).is_err());
+            assert_eq!(stream.output, vec![0x88, 0x02, 0x03, 0xEA]);
+        }
+
+        #[test]
+        fn test_parse_message_invalid_utf8_fragments() {
+            // Autobahn 6.3.1, invalid sequence split across fragments
+            assert_fails(&[&[0x01, 0x01, 0xCE], &[0x80, 0x02, 0xBA, 0xE1]], CloseCode::INVALID_PAYLOAD);
+        }
+
+        #[test]
+        fn test_parse_message_close_payloads() {
+            // Autobahn 7.3.2, 7.5.1 and 7.9.x
+            assert_fails(&[&[0x88, 0x01, 0x03]], CloseCode::PROTOCOL_ERROR);
+            assert_fails(&[&[0x88, 0x04, 0x03, 0xE8, 0xCE, 0x28]], CloseCode::INVALID_PAYLOAD);
+
+            for code in [0u16, 999, 1004, 1005, 1006, 1015, 1016, 1100, 2000, 2999, 5000].iter() {
+                let [high, low] = code.to_be_bytes();
+
+                assert_fails(&[&[0x88, 0x02, high, low]], CloseCode::PROTOCOL_ERROR);
+            }
+
+            for code in [1000u16, 1001, 1002, 1003, 1007, 1011, 3000, 3999, 4000, 4999].iter() {
+                let [high, low] = code.to_be_bytes();
+                let mut stream = Duplex::new(&[&[0x88, 0x02, high, low]]);
+
+                assert!(parse_message(&mut stream, 1024).is_ok());
+            }
+        }
+
+        #[test]
+        fn test_parse_compressed() {
+            // RFC 7692 section 7.2.3.1 and 7.2.3.2, compressed "Hello" unfragmented and fragmented
+            let mut stream = Duplex::new(&[&[0xC1, 0x07, 0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00],
+                                           &[0x41, 0x03, 0xf2, 0x48, 0xcd],
+                                           &[0x80, 0x04, 0xc9, 0xc9, 0x07, 0x00],
+                                           &[0x81, 0x05, b'H', b'e', b'l', b'l', b'o']]);
+            let mut parser = Parser::new(1024).inflater(Config::default().inflater());
+
+            for _ in 0..3 {
+                assert_eq!(parser.parse(&mut stream).unwrap(), Message::Text("Hello".to_string()));
+            }
+        }
+
+        #[test]
+        fn test_parse_compressed_misplaced_rsv1() {
+            let mut parser = Parser::new(1024).inflater(Config::default().inflater());
+
+            // compressed without negotiating, on a continuation frame and on a ping
+            assert_fails(&[&[0xC1, 0x01, b'a']], CloseCode::PROTOCOL_ERROR);
+            assert!(parser.parse(Duplex::new(&[&[0x01, 0x01, b'a'], &[0xC0, 0x01, b'b']])).is_err());
+            assert!(parser.parse(Duplex::new(&[&[0xC9, 0x00]])).is_err());
+            assert!(parser.parse(Duplex::new(&[&[0xC1, 0x03, 0xFF, 0xFF, 0xFF]])).is_err());
+        }
+
+        #[test]
+        fn test_parse_compressed_too_big() {
+            let compressed = Config::default().deflater().compress(&[b'a'; 2048]).unwrap();
+            let frame = [&[0xC2, compressed.len() as u8][..], &compressed].concat();
+            let mut stream = Duplex::new(&[&frame]);
+
+            assert!(matches!(Parser::new(1024).inflater(Config::default().inflater()).parse(&mut stream),
+                             Err(WebSocketError::TooBig(1025))));
+            assert_eq!(stream.output, vec![0x88, 0x02, 0x03, 0xF1]);
+        }
+
+        #[test]
+        fn test_parse_as_client() {
+            let mut stream = Duplex::unmasked(&[&[0x89, 0x02, b'h', b'i'], &[0x81, 0x02, b'o', b'k']]);
+
+            assert_eq!(Parser::new(1024).client().parse(&mut stream).unwrap(), Message::Text("ok".to_string()));
+
+            let (header, payload) = parse_frame(&mut &stream.output[..], DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+
+            assert!(header.is_masked);
+            assert_eq!(header.opcode, Opcode::PONG);
+            assert_eq!(payload, b"hi");
+        }
+
+        #[test]
+        fn test_parse_as_client_masked() {
+            let mut stream = Duplex::new(&[&[0x81, 0x02, b'o', b'k']]);
+
+            assert!(Parser::new(1024).client().parse(&mut stream).is_err());
+
+            let (header, payload) = parse_frame(&mut &stream.output[..], DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+
+            assert!(header.is_masked);
+            assert_eq!(payload, vec![0x03, 0xEA]);
+        }
+
+        #[test]
+        fn test_parse_message_partial_reads() {
+            let mut stream = Duplex::new(&[&[0x81, 0x05, b'H', b'e', b'l', b'l', b'o']]);
+            stream.read_size = 1;
+
+            assert_eq!(parse_message(&mut stream, 1024).unwrap(), Message::Text("Hello".to_string()));
+        }
+
+        #[test]
+        fn test_parse_message_truncated() {
+            let mut stream = Duplex::new(&[&[0x81, 0x05, b'H', b'e']]);
+
+            assert!(matches!(parse_message(&mut stream, 1024), Err(WebSocketError::Truncated)));
+            assert!(stream.output.is_empty());
+        }
+
+        #[test]
+        fn test_parse_message_continuation_without_start() {
+            let mut stream = Duplex::new(&[&[0x80, 0x01, b'a']]);
+
+            assert!(parse_message(&mut stream, 1024).is_err());
+            assert_eq!(stream.output, vec![0x88, 0x02, 0x03, 0xEA]);
+        }
+
+        #[test]
+        fn test_parse_message_interrupted_fragments() {
+            let mut stream = Duplex::new(&[&[0x01, 0x01, b'a'], &[0x81, 0x01, b'b']]);
+
+            assert!(parse_message(&mut stream, 1024).is_err());
+        }
+
+        #[test]
+        fn test_parse_header_opcodes() {
+            assert_eq!(parse_header([0x80, 0x00]).unwrap().opcode, Opcode::CONTINUATION);
+            assert_eq!(parse_header([0x82, 0x00]).unwrap().opcode, Opcode::BINARY);
+            assert_eq!(parse_header([0x89, 0x00]).unwrap().opcode, Opcode::PING);
+            assert_eq!(parse_header([0x8A, 0x00]).unwrap().opcode, Opcode::PONG);
+        }
+
+        #[test]
+        fn test_parse_header_reserved_opcodes() {
+            for opcode in [3, 4, 5, 6, 7, 11, 12, 13, 14, 15].iter() {
+                assert!(matches!(parse_header([0x80 | opcode, 0x00]),
+                                 Err(WebSocketError::BadOpcode(code)) if code == *opcode));
+            }
+        }
+
+        #[test]
+        fn test_parse_frame_masked_binary() {
+            // RFC 6455 section 5.7, masked "Hello" sent as binary
+            let raw = [0x82, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+            let (header, payload) = parse_frame(&mut &raw[..], DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+
+            assert_eq!(header.opcode, Opcode::BINARY);
+            assert_eq!(payload, b"Hello");
+        }
+
+        #[test]
+        fn test_parse_answers_ping() {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (server, _) = listener.accept().unwrap();
+
+            // masked ping "hi" followed by masked text "Hello"
+            client.write_all(&[0x89, 0x82, 0, 0, 0, 0, b'h', b'i']).unwrap();
+            client.write_all(&[0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58]).unwrap();
+
+            assert_eq!(parse(&server).unwrap(), Message::Text("Hello".to_string()));
+
+            let mut pong = [0; 4];
+            client.read_exact(&mut pong).unwrap();
+
+            assert_eq!(pong, [0x8A, 0x02, b'h', b'i']);
+        }
+
+        fn round_trip(length: usize, header_length: usize) {
+            let payload: Vec<u8> = (0..length).map(|i| i as u8).collect();
+            let frame = Frame::new(payload.clone(), Opcode::BINARY);
+
+            assert_eq!(frame.payload.len(), header_length + length);
+
+            let (header, parsed) = parse_frame(&mut &frame.payload[..], DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+
+            assert_eq!(header.payload_length, length);
+            assert_eq!(parsed, payload);
+        }
+
+        #[test]
+        fn test_round_trip_7_bit_length() {
+            round_trip(0, 2);
+            round_trip(125, 2);
+        }
+
+        #[test]
+        fn test_round_trip_16_bit_length() {
+            round_trip(126, 4);
+            round_trip(65535, 4);
+        }
+
+        #[test]
+        fn test_round_trip_64_bit_length() {
+            round_trip(65536, 10);
+            round_trip(1 << 20, 10);
+        }
+
+        #[test]
+        fn test_parse_frame_length_most_significant_bit() {
+            let raw = [0x82, 0x7F, 0x80, 0, 0, 0, 0, 0, 0, 1];
+
+            assert!(matches!(parse_frame(&mut &raw[..], DEFAULT_MAX_MESSAGE_SIZE), Err(WebSocketError::Protocol(_))));
+        }
+
+        #[test]
+        fn test_parse_frame_too_long() {
+            let raw = [0x82, 0x7F, 0, 0, 0x40, 0, 0, 0, 0, 0, 0xAB];
+
+            assert!(matches!(parse_frame(&mut &raw[..], 1024), Err(WebSocketError::TooBig(0x4000_0000_0000))));
+            assert_eq!(parse_frame(&mut &[0x82, 0x02, 1, 2][..], 2).unwrap().1, vec![1, 2]);
+        }
+
+        #[test]
+        fn test_parse_frame_truncated() {
+            let raw = [0x81, 0x05, 0x48, 0x65];
+
+            let err = parse_frame(&mut &raw[..], DEFAULT_MAX_MESSAGE_SIZE).unwrap_err();
+
+            assert!(matches!(err, WebSocketError::Truncated));
+            assert_eq!(Error::from(err).kind(), ErrorKind::UnexpectedEof);
         }
     }
 }
 
 pub mod request {
-    use std::net::TcpStream;
-    use std::io::{Read, BufReader, BufRead, Error, ErrorKind};
+    use std::error;
+    use std::fmt;
+    use std::io::{Read, BufRead, Error, ErrorKind};
     use std::collections::HashMap;
 
     use crate::http::request;
+    use crate::http::response::StatusCode;
 
-    pub fn parse(mut reader: BufReader<&TcpStream>) -> Result<request::Request, Error> {
-        let request_line: Result<String, Error> = reader.by_ref().lines().take(1).collect();
+    /// Header or trailer fields by lowercase name
+    type Fields = HashMap<String, String>;
 
-        match request_line {
-            Ok(req) => {
-                match parse_request_line(req) {
-                    Ok(parsed) => {
-                        let headers = to_headers(parse_get(reader));
+    /// Size limits of a request, so a client can't make the server buffer without end
+    #[derive(Debug, Clone, Copy)]
+    pub struct Limits {
+        /// Longest request line, longer ones are refused with 414 URI Too Long
+        pub max_request_line: usize,
+        /// Total size of header fields, larger ones are refused
+        /// with 431 Request Header Fields Too Large
+        pub max_headers: usize,
+        /// Largest body, by Content-Length or the sum of its chunks,
+        /// larger ones are refused with 413 Payload Too Large
+        pub max_body: usize
+    }
 
-                        Ok(request::Request::new(parsed,
-                                                 headers,
-                                                 None))
-                    },
-                    Err(e) => Err(e)
+    impl Default for Limits {
+        fn default() -> Limits {
+            Limits {
+                max_request_line: 8 * 1024,
+                max_headers: 32 * 1024,
+                max_body: 8 * 1024 * 1024
+            }
+        }
+    }
+
+    /// Request over a limit, carried by an InvalidData error, see `status`
+    #[derive(Debug)]
+    struct TooLarge {
+        status: StatusCode,
+        reason: &'static str
+    }
+
+    impl fmt::Display for TooLarge {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str(self.reason)
+        }
+    }
+
+    impl error::Error for TooLarge {}
+
+    fn too_large(status: StatusCode, reason: &'static str) -> Error {
+        Error::new(ErrorKind::InvalidData, TooLarge { status, reason })
+    }
+
+    fn is_too_large(err: &Error) -> bool {
+        err.get_ref().map(|inner| inner.is::<TooLarge>()).unwrap_or(false)
+    }
+
+    /// Status to refuse a request with when parsing it failed with InvalidData,
+    /// 400 Bad Request unless it was over one of the limits
+    pub fn status(err: &Error) -> StatusCode {
+        err.get_ref()
+            .and_then(|inner| inner.downcast_ref::<TooLarge>())
+            .map(|too_large| too_large.status)
+            .unwrap_or(StatusCode::BAD_REQUEST)
+    }
+
+    /// Parse one request from reader with default limits, see `parse_with`
+    pub fn parse<R: BufRead>(reader: R) -> Result<request::Request, Error> {
+        parse_with(reader, &Limits::default())
+    }
+
+    /// Parse one request from reader. Fails with UnexpectedEof when the
+    /// reader is exhausted before a request line, i.e. the client closed
+    /// a persistent connection
+    pub fn parse_with<R: BufRead>(mut reader: R, limits: &Limits) -> Result<request::Request, Error> {
+        let (parsed, headers) = parse_head(&mut reader, limits)?;
+        let (body, trailers) = parse_body(&mut reader, &headers, limits)?;
+
+        Ok(request::Request::new(parsed,
+                                 headers,
+                                 body).with_trailers(trailers))
+    }
+
+    /// Request line and header fields, up to the empty line before the body
+    pub fn parse_head<R: BufRead>(reader: &mut R, limits: &Limits)
+                                  -> Result<(request::RequestLine, HashMap<String, String>), Error> {
+        match read_request_line(reader, limits)? {
+            Some(req) => {
+                let parsed = parse_request_line(req)?;
+                let lines = parse_get(reader, limits)?;
+
+                check_framing(&lines)?;
+
+                let headers = to_headers(lines);
+
+                Ok((parsed, headers))
+            },
+            None => Err(Error::new(ErrorKind::UnexpectedEof, "Empty request"))
+        }
+    }
+
+    /// Empty lines before the request line are ignored (RFC 7230 section 3.5),
+    /// e.g. a stray CRLF after the body of a previous request
+    fn read_request_line<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Option<String>, Error> {
+        loop {
+            let line = match read_line(reader, limits.max_request_line)? {
+                Some(line) => line,
+                None => return Ok(None)
+            };
+
+            let line = line.trim_end_matches(['\r', '\n']);
+
+            if !line.is_empty() {
+                return Ok(Some(line.to_string()));
+            }
+        }
+    }
+
+    /// Header lines up to the empty line ending them. Fails when the
+    /// reader fails or is exhausted before it, so nothing is left half read
+    fn parse_get<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Vec<String>, Error> {
+        let mut lines = vec![];
+        let mut left = limits.max_headers;
+
+        loop {
+            let line = read_line(reader, left)
+                .map_err(|err| match is_too_large(&err) {
+                    true => too_large(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                                      "Header fields too large"),
+                    false => err
+                })?
+                .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "Header fields not terminated"))?;
+
+            left -= line.len();
+
+            match line.trim_end_matches(['\r', '\n']) {
+                "" => return Ok(lines),
+                line => lines.push(line.to_string())
+            }
+        }
+    }
+
+    /// Line including its line break, None when the reader is exhausted before it.
+    /// Lines longer than max are refused with 414 URI Too Long
+    fn read_line<R: BufRead>(reader: &mut R, max: usize) -> Result<Option<String>, Error> {
+        let mut line = vec![];
+
+        reader.take(max as u64 + 1).read_until(b'\n', &mut line)?;
+
+        if line.len() > max {
+            return Err(too_large(StatusCode::URI_TOO_LONG, "Request line too long"));
+        }
+
+        match (line.is_empty(), line.ends_with(b"\n")) {
+            (true, _) => Ok(None),
+            (false, false) => Err(Error::new(ErrorKind::UnexpectedEof, "Line not terminated")),
+            (false, true) => String::from_utf8(line)
+                .map(Some)
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "Line is not UTF-8"))
+        }
+    }
+
+    /// Repeated framing fields could make this server and a proxy in front of it
+    /// disagree on where the body ends, so they are refused, unless Content-Length
+    /// repeats the same value (RFC 7230 section 3.3.2)
+    fn check_framing(lines: &[String]) -> Result<(), Error> {
+        let values = |name: &str| -> Vec<String> {
+            lines.iter()
+                .filter_map(|line| split_header(line))
+                .filter(|(key, _)| key == name)
+                .map(|(_, val)| val)
+                .collect()
+        };
+
+        if values("content-length").windows(2).any(|pair| pair[0] != pair[1]) {
+            return Err(Error::new(ErrorKind::InvalidData, "Conflicting Content-Length"));
+        }
+
+        match values("transfer-encoding").len() {
+            0 | 1 => Ok(()),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Repeated Transfer-Encoding"))
+        }
+    }
+
+    /// Read message body following the headers, either chunked or exactly Content-Length bytes,
+    /// along with the trailer fields of a chunked body
+    pub fn parse_body<R: BufRead>(reader: &mut R,
+                                  headers: &HashMap<String, String>,
+                                  limits: &Limits)
+                                  -> Result<(Option<Vec<u8>>, Fields), Error> {
+        if let Some(codings) = headers.get("transfer-encoding") {
+            return match is_chunked(codings) {
+                true => parse_chunked(reader, limits).map(|(body, trailers)| (Some(body), trailers)),
+                false => Err(Error::new(ErrorKind::InvalidData, "Chunked is not the final transfer coding"))
+            };
+        }
+
+        match headers.get("content-length") {
+            Some(length) => {
+                let length = parse_number(length, 10)
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Not valid Content-Length"))?;
+
+                if length > limits.max_body as u64 {
+                    return Err(too_large(StatusCode::PAYLOAD_TOO_LARGE, "Body too large"));
                 }
+
+                read_exact_body(reader, length).map(|body| (Some(body), HashMap::new()))
             },
-            Err(_) => Err(Error::new(ErrorKind::InvalidData, "Empty request"))
+            None => Ok((None, HashMap::new()))
         }
     }
 
-    fn parse_get(reader: BufReader<&TcpStream>) -> Vec<String> {
-        reader.lines()
-            .map(|line| line.unwrap_or(String::new()))
-            .take_while(|line| !line.is_empty())
-            .collect()
+    /// Chunked must be the final transfer coding of a request body (RFC 7230 section 3.3.3),
+    /// as there is no other way to tell where the body ends
+    fn is_chunked(codings: &str) -> bool {
+        codings.rsplit(',')
+            .next()
+            .map(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+            .unwrap_or(false)
+    }
+
+    /// Longest chunk size line, chunk extensions included
+    const MAX_CHUNK_LINE: usize = 1024;
+
+    // * CHUNKED TRANSFER CODING *
+    // chunk-size [ chunk-ext ] CRLF
+    // chunk-data CRLF
+    // ...
+    // 0 [ chunk-ext ] CRLF
+    // trailer fields
+    // CRLF
+    fn parse_chunked<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<(Vec<u8>, Fields), Error> {
+        let mut body = Vec::new();
+
+        loop {
+            let line = read_line(reader, MAX_CHUNK_LINE)
+                .map_err(|err| match is_too_large(&err) {
+                    true => Error::new(ErrorKind::InvalidData, "Chunk size line too long"),
+                    false => err
+                })?
+                .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "Chunked body not terminated"))?;
+
+            match parse_chunk_size(&line)? {
+                0 => break,
+                size if size > (limits.max_body - body.len()) as u64 => {
+                    return Err(too_large(StatusCode::PAYLOAD_TOO_LARGE, "Body too large"));
+                },
+                size => {
+                    body.append(&mut read_exact_body(reader, size)?);
+
+                    let mut crlf = [0; 2];
+                    reader.read_exact(&mut crlf)?;
+
+                    if &crlf != b"\r\n" {
+                        return Err(Error::new(ErrorKind::InvalidData, "Chunk not terminated by CRLF"));
+                    }
+                }
+            }
+        }
+
+        Ok((body, to_headers(parse_get(reader, limits)?)))
+    }
+
+    fn parse_chunk_size(line: &str) -> Result<u64, Error> {
+        let size = line.split(';').next().unwrap_or_default().trim();
+
+        parse_number(size, 16)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Not valid chunk size"))
+    }
+
+    /// Digits only, as from_str_radix would also take a leading sign
+    fn parse_number(digits: &str, radix: u32) -> Option<u64> {
+        match !digits.is_empty() && digits.chars().all(|c| c.is_digit(radix)) {
+            true => u64::from_str_radix(digits, radix).ok(),
+            false => None
+        }
+    }
+
+    fn read_exact_body<R: Read>(reader: &mut R, length: u64) -> Result<Vec<u8>, Error> {
+        let mut body = Vec::new();
+
+        reader.take(length).read_to_end(&mut body)?;
+
+        match body.len() as u64 == length {
+            true => Ok(body),
+            false => Err(Error::new(ErrorKind::UnexpectedEof, "Body shorter than announced"))
+        }
     }
 
     fn parse_request_line(line: String) -> Result<request::RequestLine, Error> {
@@ -136,22 +1023,31 @@ pub mod request {
     fn parse_method(x: &str) -> Option<request::Method> {
         match x {
             "GET" => Some(request::Method::GET),
+            "HEAD" => Some(request::Method::HEAD),
+            "POST" => Some(request::Method::POST),
+            "PUT" => Some(request::Method::PUT),
+            "DELETE" => Some(request::Method::DELETE),
+            "CONNECT" => Some(request::Method::CONNECT),
+            "OPTIONS" => Some(request::Method::OPTIONS),
+            "TRACE" => Some(request::Method::TRACE),
+            "PATCH" => Some(request::Method::PATCH),
+            method if is_token(method) => Some(request::Method::Extension(method.to_string())),
             _ => None
         }
     }
 
-    fn to_headers(headers: Vec<String>) -> HashMap<String, String> {
+    /// Method names are tokens as defined in RFC 7230 section 3.2.6
+    fn is_token(x: &str) -> bool {
+        !x.is_empty() && x.chars().all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+    }
+
+    pub(crate) fn to_headers(headers: Vec<String>) -> HashMap<String, String> {
         headers.iter()
-            .map(split_header)
-            .filter(|x| match x {
-                Some(_) => true,
-                None => false
-            })
-            .map(|x| x.unwrap())
+            .filter_map(|header| split_header(header))
             .collect()
     }
 
-    fn split_header(header: &String) -> Option<(String, String)> {
+    fn split_header(header: &str) -> Option<(String, String)> {
         match header.find(":") {
             Some(idx) => {
                 let (key, val) = header.split_at(idx);
@@ -187,5 +1083,207 @@ pub mod request {
 
             assert_eq!(parsed, generated)
         }
+
+        #[test]
+        fn test_parse_body() {
+            let raw = "POST /form HTTP/1.1\r\nContent-Length: 9\r\n\r\nname=rust";
+            let request = parse(raw.as_bytes()).unwrap();
+
+            assert_eq!(request.body(), Some("name=rust".as_bytes()))
+        }
+
+        #[test]
+        fn test_parse_body_binary() {
+            let mut raw = b"PUT /upload HTTP/1.1\r\nContent-Length: 4\r\n\r\n".to_vec();
+            raw.extend_from_slice(&[0, 159, 146, 150]);
+
+            let request = parse(&raw[..]).unwrap();
+
+            assert_eq!(request.body(), Some(&[0, 159, 146, 150][..]))
+        }
+
+        #[test]
+        fn test_parse_body_leaves_next_request() {
+            let raw = "POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\nokGET / HTTP/1.1\r\n\r\n";
+            let mut reader = raw.as_bytes();
+
+            assert_eq!(parse(&mut reader).unwrap().body(), Some("ok".as_bytes()));
+            assert_eq!(reader, "GET / HTTP/1.1\r\n\r\n".as_bytes())
+        }
+
+        #[test]
+        fn test_parse_body_truncated() {
+            let raw = "POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort";
+
+            assert_eq!(parse(raw.as_bytes()).unwrap_err().kind(), ErrorKind::UnexpectedEof)
+        }
+
+        #[test]
+        fn test_parse_chunked_body() {
+            let raw = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                       4\r\nWiki\r\n5;ext=1\r\npedia\r\nE\r\n in\r\n\r\nchunks.\r\n0\r\n\r\n";
+            let request = parse(raw.as_bytes()).unwrap();
+
+            assert_eq!(request.body(), Some("Wikipedia in\r\n\r\nchunks.".as_bytes()))
+        }
+
+        #[test]
+        fn test_parse_chunked_body_trailers() {
+            let raw = "POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n\
+                       3\r\nabc\r\n0\r\nExpires: never\r\n\r\n";
+            let request = parse(raw.as_bytes()).unwrap();
+
+            assert_eq!(request.body(), Some("abc".as_bytes()));
+            assert_eq!(request.trailers().get("expires"), Some(&"never".to_string()))
+        }
+
+        #[test]
+        fn test_parse_chunked_trailers_kept_apart() {
+            let raw = "POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+                       3\r\nabc\r\n0\r\nHost: evil\r\nContent-Length: 0\r\n\r\n";
+            let request = parse(raw.as_bytes()).unwrap();
+
+            assert_eq!(request.headers().get("host"), Some(&"localhost".to_string()));
+            assert_eq!(request.headers().get("content-length"), None);
+            assert_eq!(request.trailers().get("host"), Some(&"evil".to_string()))
+        }
+
+        #[test]
+        fn test_parse_chunked_not_final() {
+            let raw = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\nContent-Length: 3\r\n\r\nabc";
+
+            assert_eq!(parse(raw.as_bytes()).unwrap_err().kind(), ErrorKind::InvalidData)
+        }
+
+        #[test]
+        fn test_parse_chunked_body_over_content_length() {
+            let raw = "POST / HTTP/1.1\r\nContent-Length: 100\r\nTransfer-Encoding: chunked\r\n\r\n\
+                       2\r\nok\r\n0\r\n\r\n";
+
+            assert_eq!(parse(raw.as_bytes()).unwrap().body(), Some("ok".as_bytes()))
+        }
+
+        #[test]
+        fn test_parse_chunked_body_bad_size() {
+            let raw = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\nabc\r\n0\r\n\r\n";
+
+            assert_eq!(parse(raw.as_bytes()).unwrap_err().kind(), ErrorKind::InvalidData)
+        }
+
+        #[test]
+        fn test_parse_signed_lengths() {
+            let content_length = "POST / HTTP/1.1\r\nContent-Length: +3\r\n\r\nabc";
+            let chunk_size = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n+3\r\nabc\r\n0\r\n\r\n";
+
+            assert_eq!(parse(content_length.as_bytes()).unwrap_err().kind(), ErrorKind::InvalidData);
+            assert_eq!(parse(chunk_size.as_bytes()).unwrap_err().kind(), ErrorKind::InvalidData)
+        }
+
+        #[test]
+        fn test_parse_repeated_framing_fields() {
+            let conflicting = "POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 8\r\n\r\nabcGET / ";
+            let repeated = "POST / HTTP/1.1\r\nContent-Length: 3\r\ncontent-length: 3\r\n\r\nabc";
+            let chunked = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n\r\n\
+                           0\r\n\r\n";
+
+            assert_eq!(status(&parse(conflicting.as_bytes()).unwrap_err()), StatusCode::BAD_REQUEST);
+            assert_eq!(parse(repeated.as_bytes()).unwrap().body(), Some("abc".as_bytes()));
+            assert_eq!(status(&parse(chunked.as_bytes()).unwrap_err()), StatusCode::BAD_REQUEST)
+        }
+
+        #[test]
+        fn test_parse_chunked_body_truncated() {
+            let raw = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nab";
+
+            assert!(parse(raw.as_bytes()).is_err())
+        }
+
+        #[test]
+        fn test_parse_skips_leading_empty_lines() {
+            let raw = "\r\nGET /after HTTP/1.1\r\n\r\n";
+            let request = parse(raw.as_bytes()).unwrap();
+
+            assert_eq!(request.get_method_and_uri(), (&request::Method::GET, "/after"))
+        }
+
+        #[test]
+        fn test_parse_empty() {
+            assert_eq!(parse("".as_bytes()).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+            assert_eq!(parse("\r\n".as_bytes()).unwrap_err().kind(), ErrorKind::UnexpectedEof)
+        }
+
+        #[test]
+        fn test_parse_without_body() {
+            let raw = "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+            assert!(parse(raw.as_bytes()).unwrap().body().is_none())
+        }
+
+        #[test]
+        fn test_parse_truncated_headers() {
+            let raw = "GET / HTTP/1.1\r\nHost: localhost\r\nAccept: text/";
+
+            assert_eq!(parse(raw.as_bytes()).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+            assert_eq!(parse("GET / HTTP/1.1\r\nHost: localhost\r\n".as_bytes()).unwrap_err().kind(),
+                       ErrorKind::UnexpectedEof)
+        }
+
+        #[test]
+        fn test_parse_limits() {
+            let limits = Limits { max_request_line: 32, max_headers: 64, max_body: 16 };
+            let long_uri = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(32));
+            let long_headers = format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", "a".repeat(64));
+            let status_of = |raw: &str| status(&parse_with(raw.as_bytes(), &limits).unwrap_err());
+
+            assert_eq!(status_of(&long_uri), StatusCode::URI_TOO_LONG);
+            assert_eq!(status_of(&long_headers), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+            assert_eq!(status_of("GET / HTTP/1.1 extra\r\n\r\n"), StatusCode::BAD_REQUEST);
+            assert!(parse_with("GET / HTTP/1.1\r\nHost: localhost\r\n\r\n".as_bytes(), &limits).is_ok())
+        }
+
+        #[test]
+        fn test_parse_body_limit() {
+            let limits = Limits { max_body: 16, ..Limits::default() };
+            let status_of = |raw: &str| status(&parse_with(raw.as_bytes(), &limits).unwrap_err());
+            let chunked = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+
+            assert_eq!(status_of("POST / HTTP/1.1\r\nContent-Length: 17\r\n\r\n"), StatusCode::PAYLOAD_TOO_LARGE);
+            assert_eq!(status_of(&format!("{}8\r\n{}\r\n9\r\n", chunked, "a".repeat(8))),
+                       StatusCode::PAYLOAD_TOO_LARGE);
+            assert_eq!(status_of(&format!("{}{}1\r\n", chunked, "0".repeat(MAX_CHUNK_LINE))),
+                       StatusCode::BAD_REQUEST);
+            assert_eq!(status_of(&format!("{}FFFFFFFFFFFFFFFFFFFF\r\n", chunked)), StatusCode::BAD_REQUEST);
+
+            let body = format!("{}8\r\n{}\r\n8\r\n{}\r\n0\r\n\r\n", chunked, "a".repeat(8), "b".repeat(8));
+            assert_eq!(parse_with(body.as_bytes(), &limits).unwrap().body().unwrap().len(), 16)
+        }
+
+        #[test]
+        fn test_parse_method_standard() {
+            assert_eq!(parse_method("GET"), Some(request::Method::GET));
+            assert_eq!(parse_method("POST"), Some(request::Method::POST));
+            assert_eq!(parse_method("PATCH"), Some(request::Method::PATCH));
+            assert_eq!(parse_method("OPTIONS"), Some(request::Method::OPTIONS));
+        }
+
+        #[test]
+        fn test_parse_method_extension() {
+            assert_eq!(parse_method("PROPFIND"),
+                       Some(request::Method::Extension("PROPFIND".to_string())));
+        }
+
+        #[test]
+        fn test_parse_method_bad() {
+            assert_eq!(parse_method("GE(T"), None);
+            assert_eq!(parse_method(""), None);
+        }
+
+        #[test]
+        fn test_parse_request_line() {
+            let parsed = parse_request_line("DELETE /users/1 HTTP/1.1".to_string()).unwrap();
+            let request = request::Request::new(parsed, HashMap::new(), None);
+
+            assert_eq!(request.get_method_and_uri(), (&request::Method::DELETE, "/users/1"))
+        }
     }
 }
@@ -1,98 +1,3347 @@
 pub mod server {
-    use std::net::{TcpListener, TcpStream};
-    use std::io::{Write, BufWriter, BufReader, Error};
-    use std::thread;
+    use std::any::Any;
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashMap;
+    use std::net::{TcpListener, TcpStream, SocketAddr, Shutdown, Ipv4Addr, Ipv6Addr};
+    use std::io::{Read, BufRead, BufWriter, BufReader, Error, ErrorKind};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::{Arc, Mutex, MutexGuard, PoisonError, mpsc};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread::{self, JoinHandle};
+    use std::time::{Duration, Instant};
 
     use crate::http;
     use crate::parser;
 
-    type ResponderType = fn(&TcpStream, http::request::Request) -> Result<(), Error>;
+    pub type ResponderType = fn(&TcpStream, http::request::Request) -> Result<(), Error>;
 
-    /// Implementation of responder function that can be used in connect
+    /// Rejected connections answered at once, beyond which they are closed unanswered
+    const MAX_REJECTING: usize = 64;
+
+    /// How long a refused connection has to take its answer
+    const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// Anything that can answer a request read from the stream, i.e. a responder
+    /// function, a closure capturing its configuration or a router
+    pub trait Handler: Send + Sync + 'static {
+        fn handle(&self, stream: &TcpStream, request: http::request::Request) -> Result<(), Error>;
+    }
+
+    impl<F> Handler for F
+    where F: Fn(&TcpStream, http::request::Request) -> Result<(), Error> + Send + Sync + 'static {
+        fn handle(&self, stream: &TcpStream, request: http::request::Request) -> Result<(), Error> {
+            self(stream, request)
+        }
+    }
+
+    impl Handler for Box<dyn Handler> {
+        fn handle(&self, stream: &TcpStream, request: http::request::Request) -> Result<(), Error> {
+            (**self).handle(stream, request)
+        }
+    }
+
+    /// Handler that shares state with every request passed to inner handler.
+    /// Handlers reach it with `request.state::<S>()`, and wrapping
+    /// several times makes states of different types available
+    pub struct WithState<S, H> {
+        state: Arc<S>,
+        handler: H
+    }
+
+    pub fn with_state<S: Any + Send + Sync, H: Handler>(state: S, handler: H) -> WithState<S, H> {
+        WithState {
+            state: Arc::new(state),
+            handler
+        }
+    }
+
+    impl<S: Any + Send + Sync, H: Handler> Handler for WithState<S, H> {
+        fn handle(&self, stream: &TcpStream, mut request: http::request::Request) -> Result<(), Error> {
+            request.set_state(Arc::clone(&self.state));
+
+            self.handler.handle(stream, request)
+        }
+    }
+
+    thread_local! {
+        /// Whether the connection a worker is serving persists after the current
+        /// request, None outside of a handler called by the server
+        static PERSISTS: Cell<Option<bool>> = const { Cell::new(None) };
+
+        /// Whether the request a worker is answering is HEAD, so only the
+        /// head of its response is written
+        static HEAD: Cell<bool> = const { Cell::new(false) };
+
+        /// Server and id of the connection a worker is serving, see `on_stop`
+        static SERVING: RefCell<Option<(Arc<Shared>, usize)>> = const { RefCell::new(None) };
+    }
+
+    type Callback = Box<dyn FnOnce() + Send>;
+
+    /// Run callback once the server starts stopping, e.g. to close a websocket session
+    /// with 1001 Going Away so it can finish before the deadline. Runs at once when the
+    /// server is already stopping, and never when not called within a server's handler
+    pub fn on_stop(callback: impl FnOnce() + Send + 'static) {
+        let stopping = SERVING.with(|serving| match &*serving.borrow() {
+            Some((shared, id)) => shared.connections.on_stop(*id, Box::new(callback)),
+            None => None
+        });
+
+        if let Some(callback) = stopping {
+            callback();
+        }
+    }
+
+    /// Implementation of responder function that can be used in connect.
+    /// Chunked responses are written incrementally as their chunks are produced.
+    /// Within a handler the response tells whether the connection persists,
+    /// unless it already has a Connection header, e.g. one closing it,
+    /// and leaves out the body when answering HEAD
     pub fn respond(stream: &TcpStream,
                    response: http::response::Response) -> Result<(), Error> {
         let mut responder = BufWriter::new(stream);
+        let connection = response.headers().iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("connection"))
+            .map(|(_, value)| value.trim().eq_ignore_ascii_case("close"));
 
-        match responder.write(&response.to_bytes()) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e)
+        let response = PERSISTS.with(|persists| match (persists.get(), connection) {
+            (Some(true), None) => response.header("Connection", "keep-alive"),
+            (Some(false), None) => response.header("Connection", "close"),
+            (Some(_), Some(true)) => {
+                persists.set(Some(false));
+                response
+            },
+            _ => response
+        });
+
+        match HEAD.with(Cell::get) {
+            true => response.write_head_to(&mut responder),
+            false => response.write_to(&mut responder)
         }
     }
 
-    pub fn serve(host: &str, port: isize, responder: ResponderType) {
-        let listener = TcpListener::bind([host, ":", &port.to_string()].concat()).unwrap();
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// Number of worker threads, each serving one connection at a time.
+        /// Long-lived connections like websockets hold their worker until closed
+        pub workers: usize,
+        /// Accepted connections waiting for a free worker. Connections
+        /// beyond this are answered with 503 Service Unavailable
+        pub queue_size: usize,
+        /// How long a persistent connection may wait for its next request,
+        /// zero waits forever
+        pub keep_alive: Duration,
+        /// Requests served on one connection before it is closed
+        pub max_requests: usize,
+        /// How long a client has to send the request line and header fields,
+        /// however slowly it sends them. Zero waits forever
+        pub request_timeout: Duration,
+        /// Size limits of requests
+        pub limits: parser::request::Limits
+    }
 
-        for stream in listener.incoming() {
-            thread::spawn(move || {
-                connect(stream, responder);
+    impl Default for Config {
+        fn default() -> Config {
+            Config {
+                workers: 64,
+                queue_size: 256,
+                keep_alive: Duration::from_secs(5),
+                max_requests: 100,
+                request_timeout: Duration::from_secs(10),
+                limits: parser::request::Limits::default()
+            }
+        }
+    }
+
+    /// Connection counters of a running server
+    #[derive(Debug, Default)]
+    pub struct Stats {
+        active: AtomicUsize,
+        queued: AtomicUsize
+    }
+
+    impl Stats {
+        /// Connections being served by a worker
+        pub fn active(&self) -> usize {
+            self.active.load(Ordering::SeqCst)
+        }
+
+        /// Connections waiting for a free worker
+        pub fn queued(&self) -> usize {
+            self.queued.load(Ordering::SeqCst)
+        }
+    }
+
+    /// Connections being served, so that stopping server can close idle
+    /// persistent connections right away and the rest once deadline passes
+    #[derive(Default)]
+    struct Connections {
+        registry: Mutex<Registry>
+    }
+
+    #[derive(Default)]
+    struct Registry {
+        next_id: usize,
+        streams: HashMap<usize, (TcpStream, bool)>,
+        on_stop: HashMap<usize, Vec<Callback>>,
+        stopping: bool,
+        closed: bool
+    }
+
+    impl Connections {
+        fn registry(&self) -> MutexGuard<'_, Registry> {
+            self.registry.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        /// None when connections have already been closed for good
+        fn register(&self, stream: &TcpStream) -> Option<usize> {
+            let mut registry = self.registry();
+            let stream = stream.try_clone().ok()?;
+
+            match registry.closed {
+                true => None,
+                false => {
+                    let id = registry.next_id;
+                    registry.next_id += 1;
+                    registry.streams.insert(id, (stream, false));
+
+                    Some(id)
+                }
+            }
+        }
+
+        fn set_idle(&self, id: usize, idle: bool) {
+            if let Some((_, is_idle)) = self.registry().streams.get_mut(&id) {
+                *is_idle = idle;
+            }
+        }
+
+        fn deregister(&self, id: usize) {
+            let mut registry = self.registry();
+
+            registry.streams.remove(&id);
+            registry.on_stop.remove(&id);
+        }
+
+        /// Callback is handed back when the server is already stopping
+        fn on_stop(&self, id: usize, callback: Callback) -> Option<Callback> {
+            let mut registry = self.registry();
+
+            match registry.stopping {
+                true => Some(callback),
+                false => {
+                    registry.on_stop.entry(id).or_default().push(callback);
+                    None
+                }
+            }
+        }
+
+        /// Callbacks registered so far, later ones are handed back at once
+        fn stop(&self) -> Vec<Callback> {
+            let mut registry = self.registry();
+            registry.stopping = true;
+
+            registry.on_stop.drain().flat_map(|(_, callbacks)| callbacks).collect()
+        }
+
+        /// Close connections waiting for their next request
+        fn close_idle(&self) {
+            for (stream, _) in self.registry().streams.values().filter(|(_, idle)| *idle) {
+                stream.shutdown(Shutdown::Both).unwrap_or_default();
+            }
+        }
+
+        /// Close every connection, which fails any read or write blocking a handler
+        fn close_all(&self) {
+            let mut registry = self.registry();
+            registry.closed = true;
+
+            for (stream, _) in registry.streams.values() {
+                stream.shutdown(Shutdown::Both).unwrap_or_default();
+            }
+        }
+    }
+
+    /// State shared by the accepting thread, the workers and the handle
+    struct Shared {
+        config: Config,
+        stats: Arc<Stats>,
+        connections: Connections,
+        stopping: AtomicBool,
+        rejecting: Arc<AtomicUsize>
+    }
+
+    impl Shared {
+        fn is_stopping(&self) -> bool {
+            self.stopping.load(Ordering::SeqCst)
+        }
+    }
+
+    /// Server with a fixed number of workers and a bounded accept queue
+    pub struct Server {
+        config: Config,
+        stats: Arc<Stats>
+    }
+
+    impl Server {
+        pub fn new(config: Config) -> Server {
+            Server {
+                config,
+                stats: Arc::new(Stats::default())
+            }
+        }
+
+        /// Counters can be shared with handlers, e.g. `with_state(server.stats(), router)`
+        pub fn stats(&self) -> Arc<Stats> {
+            Arc::clone(&self.stats)
+        }
+
+        /// Bind to host and port and serve connections in the background
+        pub fn serve(self, host: &str, port: isize, handler: impl Handler) -> Result<Handle, Error> {
+            let listener = TcpListener::bind([host, ":", &port.to_string()].concat())?;
+
+            self.listen(listener, handler)
+        }
+
+        /// Serve connections of an already bound listener in the background
+        pub fn listen(self, listener: TcpListener, handler: impl Handler) -> Result<Handle, Error> {
+            let addr = listener.local_addr()?;
+            let (sender, receiver) = mpsc::sync_channel(self.config.queue_size);
+            let receiver = Arc::new(Mutex::new(receiver));
+            let handler = Arc::new(handler);
+            let shared = Arc::new(Shared {
+                config: self.config,
+                stats: self.stats,
+                connections: Connections::default(),
+                stopping: AtomicBool::new(false),
+                rejecting: Arc::new(AtomicUsize::new(0))
             });
+
+            let mut threads: Vec<JoinHandle<()>> = (0..shared.config.workers.max(1))
+                .map(|_| {
+                    let receiver = Arc::clone(&receiver);
+                    let handler = Arc::clone(&handler);
+                    let shared = Arc::clone(&shared);
+
+                    thread::spawn(move || work(receiver, &*handler, &shared))
+                })
+                .collect();
+
+            let acceptor = Arc::clone(&shared);
+
+            // queue is dropped when accepting stops, so workers exit once it is drained
+            threads.push(thread::spawn(move || accept(listener, sender, &acceptor)));
+
+            Ok(Handle { addr, shared, threads })
         }
     }
 
-    /// Read request from client and then pass it to responder
-    fn connect(stream: Result<TcpStream, Error>, responder: ResponderType) {
-        match stream {
-            Ok(stream) => {
-                inquire(&stream).and_then(|request| responder(&stream, request))
-                    .unwrap_or_default();
-            },
-            Err(_) => {}
-        };
+    /// Serve with default configuration, e.g. `serve("0.0.0.0", 8080, router)?.join()`
+    pub fn serve(host: &str, port: isize, handler: impl Handler) -> Result<Handle, Error> {
+        Server::new(Config::default()).serve(host, port, handler)
+    }
+
+    /// Handle of a server running in the background
+    pub struct Handle {
+        addr: SocketAddr,
+        shared: Arc<Shared>,
+        threads: Vec<JoinHandle<()>>
     }
 
-    fn inquire(stream: &TcpStream) -> Result<http::request::Request, Error> {
-        let inquirer = BufReader::new(stream);
+    impl Handle {
+        pub fn local_addr(&self) -> SocketAddr {
+            self.addr
+        }
+
+        pub fn stats(&self) -> Arc<Stats> {
+            Arc::clone(&self.shared.stats)
+        }
+
+        /// Stopper can be moved to another thread, e.g. one waiting for SIGTERM,
+        /// while this thread waits in `join`
+        pub fn stopper(&self) -> Stopper {
+            Stopper {
+                addr: self.addr,
+                shared: Arc::clone(&self.shared)
+            }
+        }
+
+        /// Wait until server has been stopped and every worker has finished
+        pub fn join(self) {
+            for thread in self.threads {
+                thread.join().unwrap_or_default();
+            }
+        }
 
-        parser::request::parse(inquirer)
+        /// Stop server and wait for it, see `Stopper::stop`
+        pub fn shutdown(self, deadline: Duration) {
+            self.stopper().stop(deadline);
+            self.join();
+        }
     }
-}
 
-pub mod websocket {
-    use std::net::TcpStream;
-    use std::io::{Error, ErrorKind};
-    use super::server;
-    use crate::http;
+    #[derive(Clone)]
+    pub struct Stopper {
+        addr: SocketAddr,
+        shared: Arc<Shared>
+    }
+
+    impl Stopper {
+        /// Stop accepting connections and close idle persistent connections. In-flight
+        /// requests and websocket sessions have until deadline to finish, after which
+        /// their connections are closed. Handlers learn of it through `on_stop`, e.g.
+        /// websocket sessions are sent 1001 Going Away. Returns once no connection is left open
+        pub fn stop(&self, deadline: Duration) {
+            let started = Instant::now();
+            let stats = &self.shared.stats;
+
+            self.shared.stopping.store(true, Ordering::SeqCst);
+            self.wake_acceptor();
+            self.shared.connections.close_idle();
+
+            for callback in self.shared.connections.stop() {
+                callback();
+            }
 
-    pub trait Communicator<T> {
-        fn protocol(&self) -> &str;
-        fn receive(&self, stream: &TcpStream) -> Result<Option<T>, Error>;
-        fn send(&self, stream: &TcpStream, msg: T) -> Result<(), Error>;
+            while (stats.active() > 0 || stats.queued() > 0) && started.elapsed() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            self.shared.connections.close_all();
+        }
+
+        /// Accepting thread is blocked until the next connection, so make one
+        fn wake_acceptor(&self) {
+            let mut addr = self.addr;
+
+            if addr.ip().is_unspecified() {
+                match addr {
+                    SocketAddr::V4(_) => addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+                    SocketAddr::V6(_) => addr.set_ip(Ipv6Addr::LOCALHOST.into())
+                }
+            }
+
+            TcpStream::connect(addr).map(|_| ()).unwrap_or_default();
+        }
     }
 
-    /// Communicate with single client via websocket
-    /// by reading their message and then sending them message
-    pub fn echo_chamber<T> (stream: &TcpStream,
-                            request: http::request::Request,
-                            communicator: impl Communicator<T>) -> Result<(), Error> {
-        upgrade(stream, request, communicator.protocol()).unwrap();
+    /// Queue accepted connections for workers until stopped
+    fn accept(listener: TcpListener, sender: mpsc::SyncSender<TcpStream>, shared: &Shared) {
+        for stream in listener.incoming() {
+            if shared.is_stopping() {
+                break;
+            }
+
+            if let Ok(stream) = stream {
+                shared.stats.queued.fetch_add(1, Ordering::SeqCst);
+
+                if let Err(mpsc::TrySendError::Full(stream)) = sender.try_send(stream) {
+                    shared.stats.queued.fetch_sub(1, Ordering::SeqCst);
 
+                    // answered on a thread of its own, so a slow client can't stall accepting
+                    let rejecting = Arc::clone(&shared.rejecting);
+
+                    if rejecting.fetch_add(1, Ordering::SeqCst) < MAX_REJECTING {
+                        thread::spawn(move || {
+                            reject(&stream).unwrap_or_default();
+                            rejecting.fetch_sub(1, Ordering::SeqCst);
+                        });
+                    } else {
+                        rejecting.fetch_sub(1, Ordering::SeqCst);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Serve queued connections one at a time until the queue is dropped
+    fn work(receiver: Arc<Mutex<mpsc::Receiver<TcpStream>>>, handler: &impl Handler, shared: &Arc<Shared>) {
         loop {
-            match communicator.receive(stream) {
-                Ok(Some(msg)) => {
-                    match communicator.send(stream, msg) {
-                        Ok(_) => {},
-                        Err(err) => { break Err(err); }
+            let stream = match receiver.lock() {
+                Ok(receiver) => receiver.recv(),
+                Err(_) => break
+            };
+
+            match stream {
+                Ok(stream) => {
+                    let stats = &shared.stats;
+
+                    stats.active.fetch_add(1, Ordering::SeqCst);
+                    stats.queued.fetch_sub(1, Ordering::SeqCst);
+
+                    if let Some(id) = shared.connections.register(&stream) {
+                        SERVING.with(|serving| serving.replace(Some((Arc::clone(shared), id))));
+
+                        // panicking handler takes its connection down with it, not the worker
+                        let served = panic::catch_unwind(AssertUnwindSafe(|| {
+                            connect(&stream, id, handler, shared)
+                        }));
+
+                        if served.is_err() {
+                            PERSISTS.with(|cell| cell.set(None));
+                            HEAD.with(|cell| cell.set(false));
+                        }
+
+                        SERVING.with(|serving| serving.replace(None));
+                        shared.connections.deregister(id);
                     }
+
+                    stats.active.fetch_sub(1, Ordering::SeqCst);
                 },
-                Ok(None) => { break Ok(()); }
-                Err(err) => { break Err(err); }
+                Err(_) => break
             }
         }
     }
 
-    fn upgrade(stream: &TcpStream,
-               request: http::request::Request,
-               protocol: &str) -> Result<(), Error> {
-        let is_ok = match request.get_websocket_protocol() {
-            Some(protos) => protos.contains(&protocol),
-            None => true
-        };
+    /// Answer without reading the request when all workers are busy and the queue is full
+    fn reject(stream: &TcpStream) -> Result<(), Error> {
+        refuse(stream, http::response::error(http::response::StatusCode::SERVICE_UNAVAILABLE)
+                   .header("Retry-After", "1"))
+    }
+
+    /// Answer and close the connection without reading the rest of the request. Client
+    /// has REJECT_TIMEOUT in total to take the answer, however slowly it reads or writes
+    fn refuse(stream: &TcpStream, response: http::response::Response) -> Result<(), Error> {
+        let deadline = Instant::now() + REJECT_TIMEOUT;
+
+        stream.set_write_timeout(Some(REJECT_TIMEOUT))?;
+        respond(stream, response.header("Connection", "close"))?;
+
+        // closing with the request unread resets the connection, which may
+        // discard the response before the client reads it
+        stream.shutdown(Shutdown::Write)?;
+
+        let mut reader = stream.take(64 * 1024);
+        let mut buf = [0; 4096];
+
+        while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+            if timeout.is_zero() || stream.set_read_timeout(Some(timeout)).is_err() {
+                break;
+            }
+
+            match reader.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stream of a connection whose reads fail once the deadline has passed
+    struct Deadline<'a> {
+        stream: &'a TcpStream,
+        deadline: Option<Instant>
+    }
+
+    impl Read for Deadline<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+            if let Some(deadline) = self.deadline {
+                let timeout = deadline.checked_duration_since(Instant::now())
+                    .filter(|timeout| !timeout.is_zero())
+                    .ok_or_else(|| Error::new(ErrorKind::TimedOut, "Request took too long"))?;
+
+                self.stream.set_read_timeout(Some(timeout))?;
+            }
+
+            let mut stream = self.stream;
+
+            stream.read(buf)
+        }
+    }
+
+    /// Read requests from client and pass them to handler one by one while the
+    /// connection persists. Pipelined requests wait in the reader's buffer and are
+    /// answered in order. Malformed requests are answered with 400 Bad Request,
+    /// and requests over the limits with 413, 414 or 431
+    fn connect(stream: &TcpStream, id: usize, handler: &impl Handler, shared: &Shared) {
+        let config = &shared.config;
+        let idle_timeout = Some(config.keep_alive).filter(|timeout| !timeout.is_zero());
+        let mut inquirer = BufReader::new(Deadline { stream, deadline: None });
+
+        for served in 1..=config.max_requests.max(1) {
+            // connection is idle between requests, first one is already on its way
+            if served > 1 {
+                shared.connections.set_idle(id, true);
+
+                if shared.is_stopping() || stream.set_read_timeout(idle_timeout).is_err() {
+                    break;
+                }
+
+                // closed by client or idle for too long
+                match inquirer.fill_buf() {
+                    Ok(buf) if !buf.is_empty() => {},
+                    _ => break
+                }
+            }
+
+            let request = match inquire(&mut inquirer, config) {
+                Ok(request) => request,
+                Err(ref e) if e.kind() == ErrorKind::InvalidData => {
+                    refuse(stream, http::response::error(parser::request::status(e))).unwrap_or_default();
+                    break;
+                },
+                // closed by client, too slow or idle for too long
+                Err(_) => break
+            };
+
+            shared.connections.set_idle(id, false);
+
+            // upgraded connections speak another protocol once handler returns
+            let persists = served < config.max_requests
+                && request.is_keep_alive()
+                && !request.is_websocket_upgrade()
+                && !shared.is_stopping();
+
+            if stream.set_read_timeout(None).is_err() {
+                break;
+            }
+
+            // handler owns the stream now and may block on it as long as it needs
+            PERSISTS.with(|cell| cell.set(Some(persists)));
+            HEAD.with(|cell| cell.set(request.get_method_and_uri().0 == &http::request::Method::HEAD));
+
+            let handled = handler.handle(stream, request);
+
+            HEAD.with(|cell| cell.set(false));
+
+            // handler may have closed the connection with its response
+            if handled.is_err() || PERSISTS.with(|cell| cell.take()) != Some(true) {
+                break;
+            }
+        }
+    }
+
+    /// Read next request. Client has request_timeout to send the request line and
+    /// header fields, while the body may take as long as it keeps on coming
+    fn inquire(inquirer: &mut BufReader<Deadline>, config: &Config) -> Result<http::request::Request, Error> {
+        let stream = inquirer.get_ref().stream;
+
+        inquirer.get_mut().deadline = Some(config.request_timeout)
+            .filter(|timeout| !timeout.is_zero())
+            .map(|timeout| Instant::now() + timeout);
+
+        let (line, headers) = parser::request::parse_head(inquirer, &config.limits)?;
+
+        inquirer.get_mut().deadline = None;
+        stream.set_read_timeout(Some(config.keep_alive).filter(|timeout| !timeout.is_zero()))?;
+
+        let (body, trailers) = parser::request::parse_body(inquirer, &headers, &config.limits)?;
+
+        Ok(http::request::Request::new(line, headers, body).with_trailers(trailers))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::io::{Read, Write};
+
+        const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+
+        fn request(stream: &mut TcpStream) -> String {
+            let mut response = String::new();
+
+            stream.write_all(REQUEST).unwrap();
+            stream.read_to_string(&mut response).unwrap();
+
+            response
+        }
+
+        fn spawn(server: Server, handler: impl Handler) -> SocketAddr {
+            server.serve("127.0.0.1", 0, handler).unwrap().local_addr()
+        }
+
+        fn echo_path(stream: &TcpStream, request: http::request::Request) -> Result<(), Error> {
+            respond(stream, http::response::ok(request.path().to_string()))
+        }
+
+        /// Response of handler to raw request, called directly rather than by a server
+        fn dispatch(handler: &impl Handler, raw: &str) -> String {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            let mut response = String::new();
+
+            handler.handle(&stream, parser::request::parse(raw.as_bytes()).unwrap()).unwrap();
+            drop(stream);
+            client.read_to_string(&mut response).unwrap();
+
+            response
+        }
+
+        #[test]
+        fn test_handle_closure() {
+            let greeting = "hello".to_string();
+            let handler = move |stream: &TcpStream, request: http::request::Request| {
+                respond(stream, http::response::ok(format!("{} {}", greeting, request.path())))
+            };
+
+            assert!(dispatch(&handler, "GET /world HTTP/1.1\r\n\r\n").ends_with("\r\n\r\nhello /world"));
+            assert!(dispatch(&echo_path, "GET /world HTTP/1.1\r\n\r\n").ends_with("\r\n\r\n/world"));
+        }
+
+        #[test]
+        fn test_handle_with_state() {
+            let count = |stream: &TcpStream, request: http::request::Request| {
+                let count = request.state::<AtomicUsize>().unwrap().fetch_add(1, Ordering::SeqCst) + 1;
+                let name = request.state::<&str>().unwrap();
+
+                respond(stream, http::response::ok(format!("{}: {}", name, count)))
+            };
+            let handler = with_state(AtomicUsize::new(0), with_state("visits", count));
+
+            assert!(dispatch(&handler, "GET / HTTP/1.1\r\n\r\n").ends_with("\r\n\r\nvisits: 1"));
+            assert!(dispatch(&handler, "GET / HTTP/1.1\r\n\r\n").ends_with("\r\n\r\nvisits: 2"));
+        }
+
+        #[test]
+        fn test_panicking_handler_keeps_worker() {
+            let server = Server::new(Config { workers: 1, ..Config::default() });
+            let stats = server.stats();
+            let addr = spawn(server, |stream: &TcpStream, request: http::request::Request| {
+                match request.path() {
+                    "/panic" => panic!("handler failed"),
+                    path => respond(stream, http::response::ok(path.to_string()))
+                }
+            });
+
+            for _ in 0..2 {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                let mut response = String::new();
+
+                stream.write_all(b"GET /panic HTTP/1.1\r\n\r\n").unwrap();
+                stream.read_to_string(&mut response).unwrap_or_default();
+
+                assert!(response.is_empty());
+            }
+
+            assert!(request(&mut TcpStream::connect(addr).unwrap()).ends_with("\r\n\r\n/"));
+
+            while stats.active() > 0 {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+
+        #[test]
+        fn test_full_queue_is_rejected() {
+            let server = Server::new(Config { workers: 1, queue_size: 1, ..Config::default() });
+            let stats = server.stats();
+
+            let (entered, wait_entered) = mpsc::channel();
+            let (release, wait_release) = mpsc::channel::<()>();
+            let wait_release = Mutex::new(wait_release);
+
+            let addr = spawn(server, move |stream: &TcpStream, _| {
+                entered.send(()).unwrap();
+                wait_release.lock().unwrap().recv().unwrap();
+
+                respond(stream, http::response::ok("done"))
+            });
+
+            // first connection occupies the only worker, second waits in the queue
+            let mut busy = TcpStream::connect(addr).unwrap();
+            busy.write_all(REQUEST).unwrap();
+            wait_entered.recv().unwrap();
+
+            let mut waiting = TcpStream::connect(addr).unwrap();
+
+            while stats.queued() < 1 {
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            assert_eq!(stats.active(), 1);
+
+            // rejected client trickling its request doesn't hold up the next one
+            let mut trickling = TcpStream::connect(addr).unwrap();
+
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    if trickling.write_all(b"G").is_err() {
+                        break;
+                    }
+
+                    thread::sleep(Duration::from_millis(20));
+                }
+            });
+
+            thread::sleep(Duration::from_millis(50));
+
+            let started = Instant::now();
+
+            assert!(request(&mut TcpStream::connect(addr).unwrap())
+                    .starts_with("HTTP/1.1 503 Service Unavailable"));
+            assert!(started.elapsed() < REJECT_TIMEOUT);
+
+            release.send(()).unwrap();
+            release.send(()).unwrap();
+
+            let mut response = String::new();
+            busy.read_to_string(&mut response).unwrap();
+
+            assert!(response.ends_with("done"));
+            assert!(request(&mut waiting).ends_with("done"));
+        }
+
+        #[test]
+        fn test_pipelined_requests_on_persistent_connection() {
+            let addr = spawn(Server::new(Config::default()), echo_path);
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut response = String::new();
+
+            stream.write_all(b"GET /first HTTP/1.1\r\n\r\n\
+                               GET /second HTTP/1.1\r\n\r\n\
+                               GET /third HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+            stream.read_to_string(&mut response).unwrap();
+
+            assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 3);
+            assert!(response.find("/first").unwrap() < response.find("/second").unwrap());
+            assert!(response.ends_with("/third"));
+        }
+
+        #[test]
+        fn test_http_1_0_closes() {
+            let addr = spawn(Server::new(Config::default()), echo_path);
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut response = String::new();
+
+            stream.write_all(b"GET /first HTTP/1.0\r\n\r\nGET /second HTTP/1.0\r\n\r\n").unwrap();
+            stream.read_to_string(&mut response).unwrap();
+
+            assert!(response.ends_with("/first"));
+        }
+
+        #[test]
+        fn test_connection_header() {
+            let addr = spawn(Server::new(Config { max_requests: 2, ..Config::default() }), echo_path);
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut response = String::new();
+
+            stream.write_all(b"GET /1 HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET /2 HTTP/1.1\r\n\r\n")
+                .unwrap();
+            stream.read_to_string(&mut response).unwrap();
+
+            let (first, second) = response.split_at(response.find("\r\n\r\n/1").unwrap());
+
+            assert!(first.contains("Connection: keep-alive\r\n"));
+            assert!(second.contains("Connection: close\r\n") && second.ends_with("/2"));
+        }
+
+        #[test]
+        fn test_handler_closes_connection() {
+            let addr = spawn(Server::new(Config::default()), |stream: &TcpStream, _| {
+                respond(stream, http::response::ok("bye").header("Connection", "close"))
+            });
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut response = String::new();
+
+            stream.write_all(b"GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n").unwrap();
+            stream.read_to_string(&mut response).unwrap();
+
+            assert_eq!(response.matches("bye").count(), 1);
+        }
+
+        #[test]
+        fn test_max_requests() {
+            let addr = spawn(Server::new(Config { max_requests: 2, ..Config::default() }), echo_path);
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut response = String::new();
+
+            stream.write_all(b"GET /1 HTTP/1.1\r\n\r\nGET /2 HTTP/1.1\r\n\r\nGET /3 HTTP/1.1\r\n\r\n")
+                .unwrap();
+            stream.read_to_string(&mut response).unwrap();
+
+            assert!(response.ends_with("/2"));
+        }
+
+        #[test]
+        fn test_bind_error() {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port() as isize;
+
+            assert_eq!(serve("127.0.0.1", port, echo_path).err().unwrap().kind(),
+                       ErrorKind::AddrInUse)
+        }
+
+        #[test]
+        fn test_shutdown_drains_in_flight_request() {
+            let handle = serve("127.0.0.1", 0, |stream: &TcpStream, _| {
+                thread::sleep(Duration::from_millis(100));
+
+                respond(stream, http::response::ok("drained"))
+            }).unwrap();
+            let stats = handle.stats();
+            let mut stream = TcpStream::connect(handle.local_addr()).unwrap();
+
+            stream.write_all(REQUEST).unwrap();
+
+            while stats.active() < 1 {
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            handle.shutdown(Duration::from_secs(5));
+
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+
+            assert!(response.ends_with("drained"));
+        }
+
+        #[test]
+        fn test_shutdown_closes_idle_connections() {
+            let config = Config { keep_alive: Duration::from_secs(60), ..Config::default() };
+            let handle = Server::new(config).serve("127.0.0.1", 0, echo_path).unwrap();
+            let mut stream = TcpStream::connect(handle.local_addr()).unwrap();
+            let mut response = [0; 64];
+
+            stream.write_all(b"GET /idle HTTP/1.1\r\n\r\n").unwrap();
+            let _ = stream.read(&mut response).unwrap();
+
+            let started = Instant::now();
+            handle.shutdown(Duration::from_secs(30));
+
+            assert!(started.elapsed() < Duration::from_secs(5));
+        }
+
+        #[test]
+        fn test_shutdown_deadline_closes_busy_connections() {
+            let handle = serve("127.0.0.1", 0, |stream: &TcpStream, _| {
+                // blocks like a websocket session until the connection is closed
+                let mut buf = [0; 1];
+                let mut reader = stream;
+
+                while reader.read(&mut buf)? > 0 {}
+
+                Ok(())
+            }).unwrap();
+            let stats = handle.stats();
+            let mut stream = TcpStream::connect(handle.local_addr()).unwrap();
+
+            stream.write_all(REQUEST).unwrap();
+
+            while stats.active() < 1 {
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            handle.shutdown(Duration::from_millis(100));
+
+            assert_eq!(stats.active(), 0);
+            assert_eq!(stream.read(&mut [0; 1]).unwrap_or(0), 0);
+        }
+
+        #[test]
+        fn test_idle_timeout() {
+            let config = Config { keep_alive: Duration::from_millis(50), ..Config::default() };
+            let addr = spawn(Server::new(config), echo_path);
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut response = String::new();
+
+            stream.write_all(b"GET /only HTTP/1.1\r\n\r\n").unwrap();
+            stream.read_to_string(&mut response).unwrap();
+
+            assert!(response.ends_with("/only"));
+        }
+
+        #[test]
+        fn test_slow_request_head_times_out() {
+            let config = Config { request_timeout: Duration::from_millis(200), ..Config::default() };
+            let addr = spawn(Server::new(config), echo_path);
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut trickling = stream.try_clone().unwrap();
+            let started = Instant::now();
+
+            // every byte arrives well within the idle timeout, the head as a whole doesn't
+            thread::spawn(move || {
+                for byte in b"GET /slow HTTP/1.1\r\nHost: localhost\r\n".iter().cycle().take(200) {
+                    if trickling.write_all(&[*byte]).is_err() {
+                        break;
+                    }
+
+                    thread::sleep(Duration::from_millis(20));
+                }
+            });
+
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap_or_default();
+
+            assert!(response.is_empty());
+            assert!(started.elapsed() < Duration::from_secs(2));
+        }
+
+        #[test]
+        fn test_request_over_limits() {
+            let limits = parser::request::Limits { max_request_line: 64, max_headers: 128, max_body: 16 };
+            let addr = spawn(Server::new(Config { limits, ..Config::default() }), echo_path);
+            let long_uri = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(64));
+            let long_headers = format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", "a".repeat(128));
+            let long_body = format!("POST / HTTP/1.1\r\nContent-Length: 17\r\n\r\n{}", "a".repeat(17));
+
+            for (raw, status) in [(long_uri, "414 URI Too Long"),
+                                  (long_headers, "431 Request Header Fields Too Large"),
+                                  (long_body, "413 Payload Too Large")] {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                let mut response = String::new();
+
+                stream.write_all(raw.as_bytes()).unwrap();
+                stream.read_to_string(&mut response).unwrap();
+
+                assert!(response.starts_with(&format!("HTTP/1.1 {}", status)), "{}", response);
+            }
+        }
+    }
+}
+
+pub mod router {
+    use std::collections::HashMap;
+    use std::net::TcpStream;
+    use std::io::Error;
+
+    use super::server::{self, Handler};
+    use crate::http::request::{Request, Method};
+    use crate::http::response::{self, StatusCode};
+
+    // * ROUTE PATTERNS *
+    // /users        matches only /users
+    // /users/:id    matches /users/42, captured as param "id"
+    // /static/*     matches the rest of the path, captured as param "*"
+    enum Segment {
+        Static(String),
+        Param(String),
+        Wildcard
+    }
+
+    struct Route {
+        method: Method,
+        pattern: Vec<Segment>,
+        handler: Box<dyn Handler>
+    }
+
+    enum Resolution<'a> {
+        Found(&'a Route, HashMap<String, String>),
+        MethodNotAllowed(Vec<&'a Method>),
+        NotFound
+    }
+
+    /// Dispatches requests to handlers by method and path pattern.
+    /// Unmatched paths are answered with 404 and unmatched methods
+    /// with 405 listing the allowed methods
+    #[derive(Default)]
+    pub struct Router {
+        routes: Vec<Route>
+    }
+
+    impl Router {
+        pub fn new() -> Router {
+            Router { routes: vec![] }
+        }
+
+        /// Register handler for method and pattern. Routes are matched in
+        /// registration order. Panics if wildcard is not the last segment
+        pub fn route(mut self, method: Method, pattern: &str, handler: impl Handler) -> Router {
+            let pattern = parse_pattern(pattern);
+
+            if let Some(idx) = pattern.iter().position(|segment| matches!(segment, Segment::Wildcard)) {
+                assert!(idx == pattern.len() - 1, "Wildcard must be the last segment of a route");
+            }
+
+            self.routes.push(Route { method, pattern, handler: Box::new(handler) });
+
+            self
+        }
+
+        pub fn get(self, pattern: &str, handler: impl Handler) -> Router {
+            self.route(Method::GET, pattern, handler)
+        }
+
+        pub fn post(self, pattern: &str, handler: impl Handler) -> Router {
+            self.route(Method::POST, pattern, handler)
+        }
+
+        pub fn put(self, pattern: &str, handler: impl Handler) -> Router {
+            self.route(Method::PUT, pattern, handler)
+        }
+
+        pub fn patch(self, pattern: &str, handler: impl Handler) -> Router {
+            self.route(Method::PATCH, pattern, handler)
+        }
+
+        pub fn delete(self, pattern: &str, handler: impl Handler) -> Router {
+            self.route(Method::DELETE, pattern, handler)
+        }
+
+        /// HEAD falls back to the GET route of the path unless it has a route of its own,
+        /// the server leaves out the body of the response. So HEAD is allowed wherever GET is
+        fn resolve(&self, method: &Method, path: &str) -> Resolution<'_> {
+            let mut allowed = vec![];
+            let mut fallback = None;
+
+            for route in &self.routes {
+                if let Some(params) = match_path(&route.pattern, path) {
+                    match &route.method == method {
+                        true => return Resolution::Found(route, params),
+                        false if *method == Method::HEAD && route.method == Method::GET && fallback.is_none() =>
+                            fallback = Some((route, params)),
+                        false => {}
+                    }
+
+                    if !allowed.contains(&&route.method) {
+                        allowed.push(&route.method);
+                    }
+                }
+            }
+
+            if allowed.contains(&&Method::GET) && !allowed.contains(&&Method::HEAD) {
+                allowed.push(&Method::HEAD);
+            }
+
+            match (fallback, allowed.is_empty()) {
+                (Some((route, params)), _) => Resolution::Found(route, params),
+                (None, true) => Resolution::NotFound,
+                (None, false) => Resolution::MethodNotAllowed(allowed)
+            }
+        }
+    }
+
+    impl Handler for Router {
+        fn handle(&self, stream: &TcpStream, mut request: Request) -> Result<(), Error> {
+            let resolution = {
+                let (method, _) = request.get_method_and_uri();
+
+                self.resolve(method, request.path())
+            };
+
+            match resolution {
+                Resolution::Found(route, params) => {
+                    request.set_params(params);
+
+                    route.handler.handle(stream, request)
+                },
+                Resolution::MethodNotAllowed(allowed) => {
+                    let allow: Vec<&str> = allowed.iter().map(|method| method.as_str()).collect();
+
+                    server::respond(stream,
+                                    response::error(StatusCode::METHOD_NOT_ALLOWED)
+                                        .header("Allow", &allow.join(", ")))
+                },
+                Resolution::NotFound => server::respond(stream, response::not_found())
+            }
+        }
+    }
+
+    fn parse_pattern(pattern: &str) -> Vec<Segment> {
+        split_path(pattern)
+            .map(|segment| match segment {
+                "*" => Segment::Wildcard,
+                param if param.starts_with(':') => Segment::Param(param[1..].to_string()),
+                segment => Segment::Static(segment.to_string())
+            })
+            .collect()
+    }
+
+    /// Empty segments are ignored so that trailing and repeated slashes don't matter
+    fn split_path(path: &str) -> impl Iterator<Item = &str> {
+        path.split('/').filter(|segment| !segment.is_empty())
+    }
+
+    /// Captured parameters are percent-decoded (RFC 3986 section 2.1),
+    /// malformed escapes are kept as they are
+    fn match_path(pattern: &[Segment], path: &str) -> Option<HashMap<String, String>> {
+        let mut params = HashMap::new();
+        let mut segments = split_path(path);
+
+        for expected in pattern {
+            match expected {
+                Segment::Wildcard => {
+                    let rest: Vec<&str> = segments.by_ref().collect();
+                    params.insert("*".to_string(), percent_decode(&rest.join("/")));
+                },
+                Segment::Static(name) => match segments.next() {
+                    Some(segment) if segment == name => {},
+                    _ => return None
+                },
+                Segment::Param(name) => match segments.next() {
+                    Some(segment) => { params.insert(name.to_string(), percent_decode(segment)); },
+                    None => return None
+                }
+            }
+        }
+
+        match segments.next() {
+            Some(_) => None,
+            None => Some(params)
+        }
+    }
+
+    fn percent_decode(segment: &str) -> String {
+        let bytes = segment.as_bytes();
+        let mut decoded = Vec::with_capacity(bytes.len());
+        let mut idx = 0;
+
+        while idx < bytes.len() {
+            let escaped = bytes.get(idx + 1..idx + 3)
+                .filter(|hex| bytes[idx] == b'%' && hex.iter().all(u8::is_ascii_hexdigit))
+                .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+
+            match escaped {
+                Some(byte) => {
+                    decoded.push(byte);
+                    idx += 3;
+                },
+                None => {
+                    decoded.push(bytes[idx]);
+                    idx += 1;
+                }
+            }
+        }
+
+        String::from_utf8_lossy(&decoded).into_owned()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::io::{Read, Write};
+
+        fn noop(_: &TcpStream, _: Request) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+            pairs.iter().map(|(key, val)| (key.to_string(), val.to_string())).collect()
+        }
+
+        #[test]
+        fn test_match_path_static() {
+            let pattern = parse_pattern("/users/list");
+
+            assert_eq!(match_path(&pattern, "/users/list/"), Some(HashMap::new()));
+            assert_eq!(match_path(&pattern, "/users"), None);
+            assert_eq!(match_path(&pattern, "/users/list/all"), None);
+        }
+
+        #[test]
+        fn test_match_path_root() {
+            assert_eq!(match_path(&parse_pattern("/"), "/"), Some(HashMap::new()));
+            assert_eq!(match_path(&parse_pattern("/"), "/index.html"), None);
+        }
+
+        #[test]
+        fn test_match_path_params() {
+            let pattern = parse_pattern("/users/:id/posts/:post");
+
+            assert_eq!(match_path(&pattern, "/users/42/posts/7"),
+                       Some(params(&[("id", "42"), ("post", "7")])));
+            assert_eq!(match_path(&pattern, "/users/42/posts"), None);
+        }
+
+        #[test]
+        fn test_match_path_wildcard() {
+            let pattern = parse_pattern("/static/*");
+
+            assert_eq!(match_path(&pattern, "/static/js/bundle.js"),
+                       Some(params(&[("*", "js/bundle.js")])));
+            assert_eq!(match_path(&pattern, "/static"), Some(params(&[("*", "")])));
+            assert_eq!(match_path(&pattern, "/assets/logo.png"), None);
+        }
+
+        #[test]
+        fn test_resolve() {
+            let prefix = "users".to_string();
+            let router = Router::new()
+                .get("/users/:id", noop)
+                .delete("/users/:id", move |_: &TcpStream, _: Request| {
+                    assert_eq!(prefix, "users");
+                    Ok(())
+                })
+                .post("/users", noop);
+
+            assert!(matches!(router.resolve(&Method::DELETE, "/users/1"),
+                             Resolution::Found(route, _) if route.method == Method::DELETE));
+            assert!(matches!(router.resolve(&Method::GET, "/nowhere"), Resolution::NotFound));
+
+            match router.resolve(&Method::PUT, "/users/1") {
+                Resolution::MethodNotAllowed(allowed) =>
+                    assert_eq!(allowed, vec![&Method::GET, &Method::DELETE, &Method::HEAD]),
+                _ => panic!("Expected 405")
+            }
+
+            match router.resolve(&Method::GET, "/users") {
+                Resolution::MethodNotAllowed(allowed) => assert_eq!(allowed, vec![&Method::POST]),
+                _ => panic!("Expected 405")
+            }
+        }
+
+        #[test]
+        fn test_match_path_percent_decoded() {
+            assert_eq!(match_path(&parse_pattern("/users/:name"), "/users/J%C3%B6rg%20M"),
+                       Some(params(&[("name", "Jörg M")])));
+            assert_eq!(match_path(&parse_pattern("/files/*"), "/files/a%2Fb/100%/%zz"),
+                       Some(params(&[("*", "a/b/100%/%zz")])));
+        }
+
+        #[test]
+        fn test_resolve_head() {
+            let router = Router::new()
+                .get("/users/:id", noop)
+                .post("/users/:id", noop)
+                .route(Method::HEAD, "/status", noop)
+                .get("/status", noop);
+
+            assert!(matches!(router.resolve(&Method::HEAD, "/users/1"),
+                             Resolution::Found(route, _) if route.method == Method::GET));
+            assert!(matches!(router.resolve(&Method::HEAD, "/status"),
+                             Resolution::Found(route, _) if route.method == Method::HEAD));
+            assert!(matches!(router.resolve(&Method::HEAD, "/nowhere"), Resolution::NotFound));
+        }
+
+        #[test]
+        fn test_head_served_without_body() {
+            let router = Router::new().get("/users/:name", |stream: &TcpStream, request: Request| {
+                server::respond(stream, response::ok(request.param("name").unwrap_or_default()))
+            });
+            let addr = server::serve("127.0.0.1", 0, router).unwrap().local_addr();
+            let request = |method: &str| {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                let mut response = String::new();
+
+                write!(stream, "{} /users/a%20b HTTP/1.1\r\nConnection: close\r\n\r\n", method).unwrap();
+                stream.read_to_string(&mut response).unwrap();
+
+                response
+            };
+
+            let get = request("GET");
+            let head = request("HEAD");
+
+            assert!(get.ends_with("Content-Length: 3\r\n\r\na b"), "{}", get);
+            assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+            assert!(head.ends_with("Content-Length: 3\r\n\r\n"), "{}", head);
+        }
+
+        #[test]
+        #[should_panic]
+        fn test_wildcard_not_last() {
+            Router::new().get("/*/edit", noop);
+        }
+    }
+}
+
+pub mod websocket {
+    use std::net::{TcpStream, Shutdown};
+    use std::io::{self, Read, Write, Error, ErrorKind};
+    use std::sync::{Arc, Mutex, mpsc};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread::{self, JoinHandle};
+    use std::time::{Duration, Instant};
+    use super::server;
+    use crate::http;
+    use crate::http::deflate::{self, Deflater};
+    use crate::http::response::StatusCode;
+    use crate::http::websocket::{Frame, Message, Opcode, CloseCode, CloseFrame};
+    use crate::parser;
+    use crate::parser::websocket::{Parser, WebSocketError};
+
+    /// The only version of the protocol, RFC 6455
+    pub const VERSION: &str = "13";
+
+    /// How long the other endpoint has to answer a close frame
+    pub const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Messages a session queues for its writer before senders block
+    pub const QUEUE_SIZE: usize = 256;
+
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+    /// Pinging of idle connections, so half-open ones, e.g. after a NAT
+    /// timeout, are noticed instead of waiting on them forever
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct Keepalive {
+        /// Ping the other endpoint once it has been silent this long
+        pub interval: Duration,
+        /// Close the connection once the other endpoint has been silent this long,
+        /// should be longer than interval to give pongs time to arrive
+        pub timeout: Duration
+    }
+
+    impl Default for Keepalive {
+        fn default() -> Keepalive {
+            Keepalive {
+                interval: Duration::from_secs(30),
+                timeout: Duration::from_secs(75)
+            }
+        }
+    }
+
+    /// When the other endpoint was last heard from, i.e. any data or pong
+    #[derive(Clone)]
+    struct LastSeen(Arc<Mutex<Instant>>);
+
+    impl LastSeen {
+        fn new() -> LastSeen {
+            LastSeen(Arc::new(Mutex::new(Instant::now())))
+        }
+
+        fn touch(&self) {
+            *self.0.lock().unwrap_or_else(|err| err.into_inner()) = Instant::now();
+        }
+
+        fn get(&self) -> Instant {
+            *self.0.lock().unwrap_or_else(|err| err.into_inner())
+        }
+    }
+
+    /// What the server accepts in the opening handshake
+    /// and how the upgraded connection is kept alive
+    pub trait Handshake {
+        /// Supported subprotocols, the first one offered by the client is chosen
+        fn protocols(&self) -> &[&str] {
+            &[]
+        }
+
+        /// Origins allowed to connect from a browser, e.g. "https://example.com",
+        /// against cross-site WebSocket hijacking. Empty allows any origin
+        fn allowed_origins(&self) -> &[&str] {
+            &[]
+        }
+
+        /// permessage-deflate preferences, None to never compress
+        fn deflate(&self) -> Option<deflate::Config> {
+            None
+        }
+
+        /// Pinging of idle connections, None to wait on them forever
+        fn keepalive(&self) -> Option<Keepalive> {
+            Some(Keepalive::default())
+        }
+
+        /// Longest message accepted, longer ones close the connection with 1009
+        fn max_message_size(&self) -> usize {
+            parser::websocket::DEFAULT_MAX_MESSAGE_SIZE
+        }
+    }
+
+    /// Strict receive-then-send conversation, see `echo_chamber`
+    pub trait Communicator<T>: Handshake {
+        /// None once the connection is closed
+        fn receive(&self, connection: &Connection) -> Result<Option<T>, Error>;
+        fn send(&self, connection: &Connection, msg: T) -> Result<(), Error>;
+
+        /// Close connection with a status code, e.g. when a message can't be handled.
+        /// Receive should return None once closed
+        fn close(&self, connection: &Connection, code: CloseCode, reason: &str) -> Result<(), Error> {
+            connection.close(code, reason)
+        }
+    }
+
+    /// Upgraded connection to a single client with the state of negotiated extensions
+    pub struct Connection<'a> {
+        stream: &'a TcpStream,
+        protocol: Option<String>,
+        parser: Mutex<Parser>,
+        deflater: Option<Mutex<Deflater>>,
+        last_seen: LastSeen,
+        /// Held while writing a frame, so pings don't interleave with messages
+        writing: Mutex<()>,
+        /// Set once told the server is stopping, nothing is written after the close frame
+        going_away: AtomicBool
+    }
+
+    impl<'a> Connection<'a> {
+        /// Connection without extensions, speaking the chosen subprotocol if any
+        pub fn new(stream: &'a TcpStream, protocol: Option<&str>) -> Connection<'a> {
+            Connection {
+                stream,
+                protocol: protocol.map(|protocol| protocol.to_string()),
+                parser: Mutex::new(Parser::new(parser::websocket::DEFAULT_MAX_MESSAGE_SIZE)),
+                deflater: None,
+                last_seen: LastSeen::new(),
+                writing: Mutex::new(()),
+                going_away: AtomicBool::new(false)
+            }
+        }
+
+        /// Refuse messages longer than max_message_size, see `Parser::new`
+        pub fn max_message_size(self, max_message_size: usize) -> Connection<'a> {
+            let parser = self.parser.into_inner().unwrap_or_else(|err| err.into_inner());
+
+            Connection {
+                parser: Mutex::new(parser.max_message_size(max_message_size)),
+                ..self
+            }
+        }
+
+        /// Compress data messages both ways with agreed permessage-deflate parameters
+        pub fn deflate(self, config: &deflate::Config) -> Connection<'a> {
+            let parser = self.parser.into_inner().unwrap_or_else(|err| err.into_inner());
+
+            Connection {
+                parser: Mutex::new(parser.inflater(config.inflater())),
+                deflater: Some(Mutex::new(config.deflater())),
+                ..self
+            }
+        }
+
+        pub fn stream(&self) -> &TcpStream {
+            self.stream
+        }
+
+        /// Subprotocol agreed on during the upgrade
+        pub fn protocol(&self) -> Option<&str> {
+            self.protocol.as_deref()
+        }
+
+        /// When the other endpoint was last heard from
+        pub fn last_seen(&self) -> Instant {
+            self.last_seen.get()
+        }
+
+        /// Read next message, see `Parser::parse`
+        pub fn receive(&self) -> Result<Message, WebSocketError> {
+            self.parser.lock().unwrap_or_else(|err| err.into_inner()).parse(Guarded { connection: self })
+        }
+
+        /// Send message, compressed when permessage-deflate was negotiated
+        pub fn send(&self, msg: Message) -> Result<(), Error> {
+            let frame = match &self.deflater {
+                Some(deflater) => to_frame(msg, Some(&mut deflater.lock().unwrap_or_else(|err| err.into_inner())))?,
+                None => to_frame(msg, None)?
+            };
+
+            self.write(&frame.payload)
+        }
+
+        /// Read next message as a value of the agreed subprotocol, None once the
+        /// connection is closed. Messages that don't decode close it with 1007
+        pub fn receive_with<T>(&self, codecs: &codec::Codecs<T>) -> Result<Option<T>, Error> {
+            let msg = match self.receive()? {
+                Message::Close(_) => return Ok(None),
+                msg => msg
+            };
+
+            match codecs.get(self.protocol()).decode(msg) {
+                Ok(value) => Ok(Some(value)),
+                Err(err) => {
+                    self.close(CloseCode::INVALID_PAYLOAD, &err.to_string())?;
+
+                    Ok(None)
+                }
+            }
+        }
+
+        /// Send value encoded for the agreed subprotocol
+        pub fn send_with<T>(&self, codecs: &codec::Codecs<T>, value: &T) -> Result<(), Error> {
+            self.send(codecs.get(self.protocol()).encode(value)?)
+        }
+
+        /// See `close`
+        pub fn close(&self, code: CloseCode, reason: &str) -> Result<(), Error> {
+            let _writing = self.writing.lock().unwrap_or_else(|err| err.into_inner());
+
+            close(self.stream, code, reason)
+        }
+
+        fn write(&self, frame: &[u8]) -> Result<(), Error> {
+            let _writing = self.writing.lock().unwrap_or_else(|err| err.into_inner());
+            let mut writer = self.stream;
+
+            match self.going_away.load(Ordering::SeqCst) {
+                true => Ok(()),
+                false => writer.write_all(frame)
+            }
+        }
+
+        /// Server is stopping: send 1001 and wait for the answer only for so long
+        fn go_away(&self) {
+            let _writing = self.writing.lock().unwrap_or_else(|err| err.into_inner());
+            let mut writer = self.stream;
+
+            if !self.going_away.swap(true, Ordering::SeqCst) {
+                self.stream.set_read_timeout(Some(CLOSE_TIMEOUT)).unwrap_or_default();
+                self.stream.set_write_timeout(Some(CLOSE_TIMEOUT)).unwrap_or_default();
+                writer.write_all(&Frame::close(Some(&stopping())).payload).unwrap_or_default();
+            }
+        }
+
+        /// Other endpoint is gone: say so without waiting for an answer and shut
+        /// down the connection, so a pending receive fails instead of blocking
+        fn expire(&self) {
+            self.stream.set_write_timeout(Some(CLOSE_TIMEOUT)).unwrap_or_default();
+            self.write(&expired().payload).unwrap_or_default();
+            self.stream.shutdown(Shutdown::Both).unwrap_or_default();
+        }
+    }
+
+    /// Stream of a connection for its parser, marking the other endpoint seen
+    /// on reads and writing control frames whole between messages
+    struct Guarded<'c, 'a> {
+        connection: &'c Connection<'a>
+    }
+
+    impl Read for Guarded<'_, '_> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+            let mut stream = self.connection.stream;
+            let n = stream.read(buf)?;
+
+            if n > 0 {
+                self.connection.last_seen.touch();
+            }
+
+            Ok(n)
+        }
+    }
+
+    impl Write for Guarded<'_, '_> {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            self.write_all(buf)?;
+
+            Ok(buf.len())
+        }
+
+        fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
+            self.connection.write(buf)
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    /// Close frame sent to an endpoint that stopped answering pings
+    fn expired() -> Frame {
+        Frame::close(Some(&CloseFrame::new(CloseCode::GOING_AWAY, "Keepalive timeout")))
+    }
+
+    /// Close frame sent to every session once the server is stopping
+    fn stopping() -> CloseFrame {
+        CloseFrame::new(CloseCode::GOING_AWAY, "Server stopping")
+    }
+
+    /// Ping the other endpoint whenever it has been silent for an interval, and
+    /// expire the connection once it has been silent past the timeout. Runs until
+    /// stop is dropped or ping fails, i.e. the connection is gone
+    fn keep_alive(keepalive: Keepalive,
+                  last_seen: &LastSeen,
+                  stop: mpsc::Receiver<()>,
+                  ping: impl Fn() -> Result<(), Error>,
+                  expire: impl FnOnce()) {
+        loop {
+            match stop.recv_timeout(keepalive.interval) {
+                Err(mpsc::RecvTimeoutError::Timeout) => {},
+                _ => return
+            }
+
+            let silent = last_seen.get().elapsed();
+
+            if silent >= keepalive.timeout {
+                return expire();
+            }
+
+            if silent >= keepalive.interval && ping().is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Start the closing handshake: send close frame and wait for the other endpoint
+    /// to answer it, discarding messages that were already on their way. Connection
+    /// is shut down once answered or after CLOSE_TIMEOUT, so it doesn't linger half-closed
+    pub fn close(stream: &TcpStream, code: CloseCode, reason: &str) -> Result<(), Error> {
+        close_with(stream, Frame::close(Some(&CloseFrame::new(code, reason))))
+    }
+
+    fn close_with(stream: &TcpStream, frame: Frame) -> Result<(), Error> {
+        let mut writer = stream;
+        let deadline = Instant::now() + CLOSE_TIMEOUT;
+
+        writer.write_all(&frame.payload)?;
+
+        while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+            let mut reader = stream;
+
+            if timeout.is_zero() || stream.set_read_timeout(Some(timeout)).is_err() {
+                break;
+            }
+
+            // payloads are discarded as they're read, however long the other endpoint says they are
+            match parser::websocket::parse_frame_header(&mut reader) {
+                Ok((header, _)) if header.opcode == Opcode::CLOSE => break,
+                Ok((header, _)) => {
+                    let length = header.payload_length as u64;
+
+                    if io::copy(&mut reader.take(length), &mut io::sink()).ok() != Some(length) {
+                        break;
+                    }
+                },
+                Err(_) => break
+            }
+        }
+
+        match stream.shutdown(Shutdown::Both) {
+            Err(ref e) if e.kind() == ErrorKind::NotConnected => Ok(()),
+            result => result
+        }
+    }
+
+    fn to_frame(msg: Message, deflater: Option<&mut Deflater>) -> Result<Frame, Error> {
+        Ok(match (msg, deflater) {
+            (Message::Close(close), _) => Frame::close(close.as_ref()),
+            (Message::Text(text), Some(deflater)) =>
+                Frame::compressed(deflater.compress(text.as_bytes())?, Opcode::TEXT),
+            (Message::Binary(data), Some(deflater)) =>
+                Frame::compressed(deflater.compress(&data)?, Opcode::BINARY),
+            (msg, None) => msg.into()
+        })
+    }
+
+    /// Callbacks of a `Session`, run on the thread reading the connection
+    pub trait Events: Handshake {
+        /// Connection is upgraded, sender can be kept to push messages at any time
+        fn on_open(&self, _sender: &Sender) {}
+
+        /// Text or binary message from the other endpoint
+        fn on_message(&self, sender: &Sender, msg: Message);
+
+        /// Session ended, with the close frame of the other endpoint when it
+        /// closed the connection. Called last, also after on_error
+        fn on_close(&self, _close: Option<&CloseFrame>) {}
+
+        /// Connection failed, e.g. the other endpoint broke the protocol or vanished
+        fn on_error(&self, _err: &WebSocketError) {}
+    }
+
+    pub(crate) enum Outgoing {
+        Message(Message),
+        /// Control frame written by the parser or keepalive, e.g. pong or ping
+        Frame(Vec<u8>),
+        /// Other endpoint stopped answering pings, see `Keepalive`
+        Expire,
+        Stop
+    }
+
+    /// Handle for sending messages to a session from any thread. Messages are
+    /// queued for the writer of the session, sending fails once it has ended
+    #[derive(Clone)]
+    pub struct Sender {
+        id: usize,
+        queue: mpsc::SyncSender<Outgoing>,
+        open: Arc<AtomicBool>,
+        last_seen: LastSeen
+    }
+
+    impl Sender {
+        /// Sender without a session, its messages are left in the returned queue
+        #[cfg(test)]
+        pub(crate) fn detached(size: usize) -> (Sender, mpsc::Receiver<Outgoing>) {
+            let (queue, outgoing) = mpsc::sync_channel(size);
+            let sender = Sender {
+                id: NEXT_ID.fetch_add(1, Ordering::SeqCst),
+                queue,
+                open: Arc::new(AtomicBool::new(true)),
+                last_seen: LastSeen::new()
+            };
+
+            (sender, outgoing)
+        }
+
+        /// Identifies the session, unique within the process
+        pub fn id(&self) -> usize {
+            self.id
+        }
+
+        /// False once the session has ended
+        pub fn is_open(&self) -> bool {
+            self.open.load(Ordering::SeqCst)
+        }
+
+        /// When the other endpoint was last heard from, i.e. any data or pong
+        pub fn last_seen(&self) -> Instant {
+            self.last_seen.get()
+        }
+
+        /// Blocks while the queue is full
+        pub fn send(&self, msg: Message) -> Result<(), Error> {
+            self.queue.send(Outgoing::Message(msg))
+                .map_err(|_| ended())
+        }
+
+        /// Fails with WouldBlock instead of waiting while the queue is full,
+        /// i.e. the other endpoint doesn't keep up with messages sent to it
+        pub fn try_send(&self, msg: Message) -> Result<(), Error> {
+            match self.queue.try_send(Outgoing::Message(msg)) {
+                Ok(_) => Ok(()),
+                Err(mpsc::TrySendError::Full(_)) => Err(Error::new(ErrorKind::WouldBlock, "Queue is full")),
+                Err(mpsc::TrySendError::Disconnected(_)) => Err(ended())
+            }
+        }
+
+        pub fn text(&self, text: impl Into<String>) -> Result<(), Error> {
+            self.send(Message::Text(text.into()))
+        }
+
+        pub fn binary(&self, data: impl Into<Vec<u8>>) -> Result<(), Error> {
+            self.send(Message::Binary(data.into()))
+        }
+
+        /// Start the closing handshake, messages sent after this are dropped.
+        /// on_close is called once the other endpoint answers or CLOSE_TIMEOUT passes
+        pub fn close(&self, code: CloseCode, reason: &str) -> Result<(), Error> {
+            self.send(Message::Close(Some(CloseFrame::new(code, reason))))
+        }
+    }
+
+    fn ended() -> Error {
+        Error::new(ErrorKind::BrokenPipe, "Session has ended")
+    }
+
+    /// Full-duplex websocket session. Reader half runs on the calling thread and
+    /// dispatches events, writer half runs on its own thread writing whatever
+    /// senders queue, so messages can be pushed without waiting for one to answer
+    pub struct Session<'a> {
+        stream: &'a TcpStream,
+        protocol: Option<String>,
+        parser: Parser,
+        sender: Sender,
+        keepalive: Option<Keepalive>,
+        closing: Arc<AtomicBool>,
+        writer: JoinHandle<Result<(), Error>>
+    }
+
+    impl<'a> Session<'a> {
+        /// Answer the opening handshake and start the writer
+        pub fn upgrade(stream: &'a TcpStream,
+                       request: http::request::Request,
+                       handshake: &impl Handshake) -> Result<Session<'a>, Error> {
+            let Connection { stream, protocol, parser, deflater, last_seen, .. } = upgrade(stream, request, handshake)?;
+            let deflater = deflater.map(|deflater| deflater.into_inner().unwrap_or_else(|err| err.into_inner()));
+            let (queue, outgoing) = mpsc::sync_channel(QUEUE_SIZE);
+            let closing = Arc::new(AtomicBool::new(false));
+            let writer = {
+                let stream = stream.try_clone()?;
+                let closing = Arc::clone(&closing);
+
+                thread::spawn(move || write(stream, deflater, outgoing, closing))
+            };
+
+            Ok(Session {
+                stream,
+                protocol,
+                parser: parser.into_inner().unwrap_or_else(|err| err.into_inner()),
+                sender: Sender {
+                    id: NEXT_ID.fetch_add(1, Ordering::SeqCst),
+                    queue,
+                    open: Arc::new(AtomicBool::new(true)),
+                    last_seen
+                },
+                keepalive: handshake.keepalive(),
+                closing,
+                writer
+            })
+        }
+
+        /// Subprotocol agreed on during the upgrade
+        pub fn protocol(&self) -> Option<&str> {
+            self.protocol.as_deref()
+        }
+
+        pub fn sender(&self) -> Sender {
+            self.sender.clone()
+        }
+
+        /// When the other endpoint was last heard from, i.e. any data or pong
+        pub fn last_seen(&self) -> Instant {
+            self.sender.last_seen()
+        }
+
+        /// Read messages until the session ends, dispatching them to events.
+        /// Connection is closed once done. When the other endpoint stops answering
+        /// pings it's sent 1001 and on_close gets no close frame, i.e. 1006. It's
+        /// sent 1001 as well once the server is stopping, see `server::on_stop`
+        pub fn run(mut self, events: &impl Events) -> Result<(), Error> {
+            events.on_open(&self.sender);
+
+            let going_away = self.sender.queue.clone();
+            server::on_stop(move || {
+                // a full queue is drained only after the deadline, shutting it down anyway
+                going_away.try_send(Outgoing::Message(Message::Close(Some(stopping())))).unwrap_or_default();
+            });
+
+            let (stop, stopped) = mpsc::channel::<()>();
+            let pinger = self.keepalive.map(|keepalive| {
+                let sender = self.sender.clone();
+
+                thread::spawn(move || keep_alive(keepalive, &sender.last_seen, stopped, || {
+                    // a full queue isn't idle, the ping can be skipped
+                    match sender.queue.try_send(Outgoing::Frame(Frame::new(vec![], Opcode::PING).payload)) {
+                        Err(mpsc::TrySendError::Disconnected(_)) => Err(ended()),
+                        _ => Ok(())
+                    }
+                }, || sender.queue.send(Outgoing::Expire).unwrap_or_default()))
+            });
+
+            let (closing, lingering) = mpsc::channel::<()>();
+            let lingerer = {
+                let stream = self.stream.try_clone()?;
+
+                thread::spawn(move || linger(&stream, lingering))
+            };
+
+            let mut halves = Halves {
+                stream: self.stream,
+                queue: self.sender.queue.clone(),
+                last_seen: &self.sender.last_seen,
+                closing: closing.clone()
+            };
+
+            let result = loop {
+                match self.parser.parse(&mut halves) {
+                    Ok(Message::Close(close)) => {
+                        events.on_close(close.as_ref());
+                        break Ok(());
+                    },
+                    Ok(msg) => events.on_message(&self.sender, msg),
+                    // our close frame wasn't answered in time
+                    Err(_) if self.closing.load(Ordering::SeqCst) => {
+                        events.on_close(None);
+                        break Ok(());
+                    },
+                    Err(err) => {
+                        events.on_error(&err);
+                        events.on_close(None);
+                        break Err(err.into());
+                    }
+                }
+            };
+
+            // frames queued so far, e.g. the close frame failing the connection, are
+            // written before stopping, unless the other endpoint stopped reading them
+            self.stream.set_write_timeout(Some(CLOSE_TIMEOUT)).unwrap_or_default();
+            closing.send(()).unwrap_or_default();
+
+            drop(stop);
+            if let Some(pinger) = pinger {
+                pinger.join().unwrap_or_default();
+            }
+
+            self.sender.open.store(false, Ordering::SeqCst);
+            self.sender.queue.send(Outgoing::Stop).unwrap_or_default();
+            self.writer.join().unwrap_or(Ok(())).unwrap_or_default();
+            self.stream.shutdown(Shutdown::Both).unwrap_or_default();
+
+            drop(halves);
+            drop(closing);
+            lingerer.join().unwrap_or_default();
+
+            result
+        }
+    }
+
+    /// Once closing starts, shut down the stream unless the session is over within
+    /// CLOSE_TIMEOUT. A write already blocked on an endpoint that stopped reading
+    /// isn't bound by the write timeout set meanwhile, but fails once shut down
+    fn linger(stream: &TcpStream, closing: mpsc::Receiver<()>) {
+        if closing.recv().is_err() {
+            return;
+        }
+
+        let deadline = Instant::now() + CLOSE_TIMEOUT;
+
+        loop {
+            match closing.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(_) => {},
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    stream.shutdown(Shutdown::Both).unwrap_or_default();
+                    return;
+                },
+                Err(mpsc::RecvTimeoutError::Disconnected) => return
+            }
+        }
+    }
+
+    /// Reading side of the stream for the parser, whose
+    /// control frames are queued for the writer instead
+    struct Halves<'a> {
+        stream: &'a TcpStream,
+        queue: mpsc::SyncSender<Outgoing>,
+        last_seen: &'a LastSeen,
+        /// Told when answering a close frame, see `linger`
+        closing: mpsc::Sender<()>
+    }
+
+    impl Read for Halves<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+            let mut stream = self.stream;
+            let n = stream.read(buf)?;
+
+            if n > 0 {
+                self.last_seen.touch();
+            }
+
+            Ok(n)
+        }
+    }
+
+    impl Write for Halves<'_> {
+        /// Frames are written whole. When the writer has already stopped after
+        /// a close frame, frames are dropped as nothing may be sent after it
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            if buf[0] & 0xF == Opcode::CLOSE as u8 {
+                self.closing.send(()).unwrap_or_default();
+            }
+
+            self.queue.send(Outgoing::Frame(buf.to_vec())).unwrap_or_default();
+
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    /// Writer half of a session, stops after writing a close frame
+    fn write(mut stream: TcpStream,
+             mut deflater: Option<Deflater>,
+             outgoing: mpsc::Receiver<Outgoing>,
+             closing: Arc<AtomicBool>) -> Result<(), Error> {
+        for msg in outgoing {
+            let (frame, initiates_close) = match msg {
+                Outgoing::Message(msg) => {
+                    let is_close = matches!(msg, Message::Close(_));
+
+                    (to_frame(msg, deflater.as_mut())?.payload, is_close)
+                },
+                Outgoing::Frame(frame) => (frame, false),
+                // reader fails once the connection is shut down, as if it were closed
+                Outgoing::Expire => {
+                    closing.store(true, Ordering::SeqCst);
+                    stream.set_write_timeout(Some(CLOSE_TIMEOUT))?;
+                    stream.write_all(&expired().payload).unwrap_or_default();
+                    stream.shutdown(Shutdown::Both).unwrap_or_default();
+                    break;
+                },
+                Outgoing::Stop => break
+            };
+
+            // reader waits for the answer to our close frame only for so long
+            if initiates_close {
+                closing.store(true, Ordering::SeqCst);
+                stream.set_read_timeout(Some(CLOSE_TIMEOUT))?;
+            }
+
+            stream.write_all(&frame)?;
+
+            if frame[0] & 0xF == Opcode::CLOSE as u8 {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run a full-duplex session with a single client until it ends
+    pub fn session(stream: &TcpStream,
+                   request: http::request::Request,
+                   events: impl Events) -> Result<(), Error> {
+        Session::upgrade(stream, request, &events)?.run(&events)
+    }
+
+    /// Communicate with single client via websocket
+    /// by reading their message and then sending them message.
+    /// Client is sent 1001 once the server is stopping, see `server::on_stop`
+    pub fn echo_chamber<T> (stream: &TcpStream,
+                            request: http::request::Request,
+                            communicator: impl Communicator<T>) -> Result<(), Error> {
+        let connection = upgrade(stream, request, &communicator)?;
+        let (stop, stopped) = mpsc::channel::<()>();
+
+        // true once the server is stopping, false once the session is over
+        let (signal, signals) = mpsc::channel::<bool>();
+        let stopping = signal.clone();
+        server::on_stop(move || stopping.send(true).unwrap_or_default());
+
+        thread::scope(|scope| {
+            let connection = &connection;
+
+            if let Some(keepalive) = communicator.keepalive() {
+                scope.spawn(move || keep_alive(keepalive, &connection.last_seen, stopped, || {
+                    connection.write(&Frame::new(vec![], Opcode::PING).payload)
+                }, || connection.expire()));
+            }
+
+            scope.spawn(move || {
+                if let Ok(true) = signals.recv() {
+                    connection.go_away();
+                }
+            });
+
+            let result = loop {
+                match communicator.receive(connection) {
+                    Ok(Some(msg)) => {
+                        match communicator.send(connection, msg) {
+                            Ok(_) => {},
+                            Err(err) => { break Err(err); }
+                        }
+                    },
+                    Ok(None) => { break Ok(()); }
+                    // our close frame wasn't answered in time
+                    Err(_) if connection.going_away.load(Ordering::SeqCst) => { break Ok(()); }
+                    Err(err) => { break Err(err); }
+                }
+            };
+
+            drop(stop);
+            signal.send(false).unwrap_or_default();
+
+            result
+        })
+    }
+
+    /// Answer the opening handshake. Invalid handshakes are refused with 400,
+    /// unsupported versions with 426 and disallowed origins with 403
+    fn upgrade<'a>(stream: &'a TcpStream,
+                   request: http::request::Request,
+                   handshake: &impl Handshake) -> Result<Connection<'a>, Error> {
+        if let Err((status, reason)) = check_handshake(&request, handshake.allowed_origins()) {
+            let response = match status == StatusCode::UPGRADE_REQUIRED {
+                true => http::response::error(status).header("Sec-WebSocket-Version", VERSION),
+                false => http::response::error(status)
+            };
+
+            server::respond(stream, response.header("Connection", "close"))?;
+
+            return Err(Error::new(ErrorKind::InvalidData, reason));
+        }
+
+        let protocol = request.select_websocket_protocol(handshake.protocols());
+
+        let deflate = match (handshake.deflate(), request.headers().get("sec-websocket-extensions")) {
+            (Some(config), Some(offers)) => config.negotiate(offers),
+            _ => None
+        };
+
+        let key = request.generate_websocket_accept_value().unwrap_or_default();
+        let response = http::response::websocket(key, protocol);
+        let connection = Connection::new(stream, protocol).max_message_size(handshake.max_message_size());
+
+        match deflate {
+            Some(config) => {
+                server::respond(stream, response.header("Sec-WebSocket-Extensions",
+                                                        &config.to_header()))?;
+
+                Ok(connection.deflate(&config))
+            },
+            None => {
+                server::respond(stream, response)?;
+
+                Ok(connection)
+            }
+        }
+    }
+
+    /// Client handshake requirements of RFC 6455 section 4.2.1. Requests without
+    /// Origin don't come from a browser and aren't subject to allowed origins
+    fn check_handshake(request: &http::request::Request,
+                       allowed_origins: &[&str]) -> Result<(), (StatusCode, &'static str)> {
+        let header = |name: &str| request.headers().get(name).map(|value| value.trim());
+
+        if !request.is_websocket_upgrade() {
+            return Err((StatusCode::BAD_REQUEST, "Not a websocket upgrade"));
+        }
+
+        if header("host").is_none() {
+            return Err((StatusCode::BAD_REQUEST, "Missing Host"));
+        }
+
+        if header("sec-websocket-version") != Some(VERSION) {
+            return Err((StatusCode::UPGRADE_REQUIRED, "Unsupported websocket version"));
+        }
+
+        match header("sec-websocket-key").and_then(|key| base64::decode(key).ok()) {
+            Some(ref key) if key.len() == 16 => {},
+            _ => return Err((StatusCode::BAD_REQUEST, "Invalid Sec-WebSocket-Key"))
+        }
+
+        match header("origin") {
+            Some(origin) if !allowed_origins.is_empty()
+                && !allowed_origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)) =>
+                Err((StatusCode::FORBIDDEN, "Origin not allowed")),
+            _ => Ok(())
+        }
+    }
+
+    /// Typed messages, encoded according to the agreed subprotocol
+    pub mod codec {
+        use std::io::{Error, ErrorKind};
+        use serde::Serialize;
+        use serde::de::DeserializeOwned;
+        use crate::http::websocket::Message;
+
+        /// Encoding of values as messages of a subprotocol
+        pub trait Codec<T> {
+            /// Subprotocol name offered in the handshake, e.g. "json"
+            fn protocol(&self) -> &'static str;
+
+            fn encode(&self, value: &T) -> Result<Message, Error>;
+
+            /// Fails with InvalidData when the message isn't a valid value
+            fn decode(&self, msg: Message) -> Result<T, Error>;
+        }
+
+        /// Codecs by subprotocol, the first one is used when none was agreed on
+        pub struct Codecs<T> {
+            protocols: Vec<&'static str>,
+            codecs: Vec<Box<dyn Codec<T> + Send + Sync>>
+        }
+
+        impl<T> Codecs<T> {
+            pub fn new(codec: impl Codec<T> + Send + Sync + 'static) -> Codecs<T> {
+                Codecs {
+                    protocols: vec![codec.protocol()],
+                    codecs: vec![Box::new(codec)]
+                }
+            }
+
+            /// Also support codec, less preferred than the ones before it
+            pub fn with(mut self, codec: impl Codec<T> + Send + Sync + 'static) -> Codecs<T> {
+                self.protocols.push(codec.protocol());
+                self.codecs.push(Box::new(codec));
+                self
+            }
+
+            /// Subprotocols for `Handshake::protocols`
+            pub fn protocols(&self) -> &[&str] {
+                &self.protocols
+            }
+
+            /// Codec of an agreed subprotocol
+            pub fn get(&self, protocol: Option<&str>) -> &dyn Codec<T> {
+                let index = protocol
+                    .and_then(|protocol| self.protocols.iter().position(|p| *p == protocol))
+                    .unwrap_or(0);
+
+                self.codecs[index].as_ref()
+            }
+        }
+
+        fn invalid(err: impl ToString) -> Error {
+            Error::new(ErrorKind::InvalidData, err.to_string())
+        }
+
+        /// JSON as text messages, binary messages are accepted too
+        pub struct Json;
+
+        impl<T: Serialize + DeserializeOwned> Codec<T> for Json {
+            fn protocol(&self) -> &'static str {
+                "json"
+            }
+
+            fn encode(&self, value: &T) -> Result<Message, Error> {
+                serde_json::to_string(value)
+                    .map(Message::Text)
+                    .map_err(|err| Error::new(ErrorKind::InvalidInput, err))
+            }
+
+            fn decode(&self, msg: Message) -> Result<T, Error> {
+                match msg {
+                    Message::Text(text) => serde_json::from_str(&text).map_err(invalid),
+                    Message::Binary(data) => serde_json::from_slice(&data).map_err(invalid),
+                    Message::Close(_) => Err(invalid("Not a data message"))
+                }
+            }
+        }
+
+        /// MessagePack as binary messages
+        #[cfg(feature = "msgpack")]
+        pub struct MessagePack;
+
+        #[cfg(feature = "msgpack")]
+        impl<T: Serialize + DeserializeOwned> Codec<T> for MessagePack {
+            fn protocol(&self) -> &'static str {
+                "msgpack"
+            }
+
+            fn encode(&self, value: &T) -> Result<Message, Error> {
+                rmp_serde::to_vec_named(value)
+                    .map(Message::Binary)
+                    .map_err(|err| Error::new(ErrorKind::InvalidInput, err))
+            }
+
+            fn decode(&self, msg: Message) -> Result<T, Error> {
+                match msg {
+                    Message::Binary(data) => rmp_serde::from_slice(&data).map_err(invalid),
+                    _ => Err(invalid("MessagePack is sent as binary"))
+                }
+            }
+        }
+
+        /// CBOR as binary messages
+        #[cfg(feature = "cbor")]
+        pub struct Cbor;
+
+        #[cfg(feature = "cbor")]
+        impl<T: Serialize + DeserializeOwned> Codec<T> for Cbor {
+            fn protocol(&self) -> &'static str {
+                "cbor"
+            }
+
+            fn encode(&self, value: &T) -> Result<Message, Error> {
+                let mut data = vec![];
+
+                ciborium::ser::into_writer(value, &mut data)
+                    .map(|_| Message::Binary(data))
+                    .map_err(|err| Error::new(ErrorKind::InvalidInput, err.to_string()))
+            }
+
+            fn decode(&self, msg: Message) -> Result<T, Error> {
+                match msg {
+                    Message::Binary(data) => ciborium::de::from_reader(data.as_slice()).map_err(invalid),
+                    _ => Err(invalid("CBOR is sent as binary"))
+                }
+            }
+        }
+
+        /// Text messages as they are
+        pub struct Text;
+
+        impl Codec<String> for Text {
+            fn protocol(&self) -> &'static str {
+                "text"
+            }
+
+            fn encode(&self, value: &String) -> Result<Message, Error> {
+                Ok(Message::Text(value.clone()))
+            }
+
+            fn decode(&self, msg: Message) -> Result<String, Error> {
+                match msg {
+                    Message::Text(text) => Ok(text),
+                    _ => Err(invalid("Expected a text message"))
+                }
+            }
+        }
+
+        /// Binary messages as they are
+        pub struct Binary;
+
+        impl Codec<Vec<u8>> for Binary {
+            fn protocol(&self) -> &'static str {
+                "binary"
+            }
+
+            fn encode(&self, value: &Vec<u8>) -> Result<Message, Error> {
+                Ok(Message::Binary(value.clone()))
+            }
+
+            fn decode(&self, msg: Message) -> Result<Vec<u8>, Error> {
+                match msg {
+                    Message::Binary(data) => Ok(data),
+                    _ => Err(invalid("Expected a binary message"))
+                }
+            }
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+
+            type Value = serde_json::Value;
+
+            fn round_trip(codec: impl Codec<Value>, value: &Value) -> Value {
+                codec.decode(codec.encode(value).unwrap()).unwrap()
+            }
+
+            #[test]
+            fn test_round_trips() {
+                let value = serde_json::json!({ "id": 1, "tags": ["a", "b"] });
+
+                assert_eq!(round_trip(Json, &value), value);
+                #[cfg(feature = "msgpack")]
+                assert_eq!(round_trip(MessagePack, &value), value);
+                #[cfg(feature = "cbor")]
+                assert_eq!(round_trip(Cbor, &value), value);
+
+                assert_eq!(Text.decode(Text.encode(&"hi".to_string()).unwrap()).unwrap(), "hi");
+                assert_eq!(Binary.decode(Binary.encode(&vec![1, 2]).unwrap()).unwrap(), vec![1, 2]);
+            }
+
+            #[test]
+            fn test_invalid_data() {
+                let json: Result<Value, _> = Json.decode(Message::Text("{".to_string()));
+
+                assert_eq!(json.unwrap_err().kind(), ErrorKind::InvalidData);
+                assert_eq!(Text.decode(Message::Binary(vec![])).unwrap_err().kind(), ErrorKind::InvalidData);
+                assert_eq!(Binary.decode(Message::Text("".to_string())).unwrap_err().kind(), ErrorKind::InvalidData);
+            }
+
+            #[test]
+            fn test_codec_by_protocol() {
+                let codecs: Codecs<String> = Codecs::new(Text).with(Json);
+
+                assert_eq!(codecs.protocols(), &["text", "json"]);
+                assert_eq!(codecs.get(Some("json")).protocol(), "json");
+                assert_eq!(codecs.get(Some("xml")).protocol(), "text");
+                assert_eq!(codecs.get(None).protocol(), "text");
+            }
+        }
+    }
+
+    /// Client side of the protocol, e.g. for talking to other services or testing handlers
+    pub mod client {
+        use std::net::TcpStream;
+        use std::io::{Read, Write, Error, ErrorKind};
+        use std::sync::Mutex;
+        use super::{VERSION, to_frame, close_with};
+        use crate::http::request::websocket_accept_value;
+        use crate::http::websocket::{Frame, Message, CloseCode, CloseFrame, masking_key};
+        use crate::parser;
+        use crate::parser::websocket::{Parser, WebSocketError, DEFAULT_MAX_MESSAGE_SIZE};
+
+        /// Longest response head accepted in the opening handshake
+        const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+        /// Connection to a server, with the same message API as `Connection`.
+        /// Frames sent are masked with a random key
+        pub struct Client {
+            stream: TcpStream,
+            protocol: Option<String>,
+            parser: Mutex<Parser>
+        }
+
+        /// Connect to a ws:// url, e.g. "ws://localhost:8080/ws", offering
+        /// subprotocols in order of preference
+        pub fn connect(url: &str, protocols: &[&str]) -> Result<Client, Error> {
+            let rest = url.strip_prefix("ws://")
+                .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Only ws:// urls are supported"))?;
+
+            let (host, path) = match rest.find('/') {
+                Some(i) => rest.split_at(i),
+                None => (rest, "/")
+            };
+
+            let stream = match host.rsplit(':').next().map(|port| port.parse::<u16>()) {
+                Some(Ok(_)) => TcpStream::connect(host)?,
+                _ => TcpStream::connect((host.trim_start_matches('[').trim_end_matches(']'), 80))?
+            };
+
+            Client::handshake(stream, host, path, protocols)
+        }
+
+        impl Client {
+            /// Opening handshake over a connected stream. Fails with ConnectionRefused
+            /// when server doesn't switch protocols and InvalidData when its answer is wrong
+            pub fn handshake(mut stream: TcpStream,
+                             host: &str,
+                             path: &str,
+                             protocols: &[&str]) -> Result<Client, Error> {
+                let key = base64::encode(&rand::random::<[u8; 16]>());
+                let mut request = format!("GET {} HTTP/1.1\r\nHost: {}\r\n\
+                                           Connection: Upgrade\r\nUpgrade: websocket\r\n\
+                                           Sec-WebSocket-Version: {}\r\nSec-WebSocket-Key: {}\r\n",
+                                          path, host, VERSION, key);
+
+                if !protocols.is_empty() {
+                    request.push_str(&format!("Sec-WebSocket-Protocol: {}\r\n", protocols.join(", ")));
+                }
+
+                request.push_str("\r\n");
+                stream.write_all(request.as_bytes())?;
+
+                let head = read_head(&stream)?;
+                let mut lines = head.split("\r\n");
+                let status = lines.next().unwrap_or_default();
+
+                if status.split(' ').nth(1) != Some("101") {
+                    return Err(Error::new(ErrorKind::ConnectionRefused, format!("Upgrade refused: {}", status)));
+                }
+
+                let headers = parser::request::to_headers(lines.map(|line| line.to_string()).collect());
+                let has = |name: &str, value: &str| headers.get(name)
+                    .map(|values| values.split(',').any(|option| option.trim().eq_ignore_ascii_case(value)))
+                    .unwrap_or(false);
+
+                if !has("upgrade", "websocket") || !has("connection", "upgrade") {
+                    return Err(invalid("Server didn't upgrade to websocket"));
+                }
+
+                if headers.get("sec-websocket-accept") != Some(&websocket_accept_value(&key)) {
+                    return Err(invalid("Invalid Sec-WebSocket-Accept"));
+                }
+
+                if headers.contains_key("sec-websocket-extensions") {
+                    return Err(invalid("Server accepted an extension that wasn't offered"));
+                }
+
+                let protocol = match headers.get("sec-websocket-protocol") {
+                    Some(protocol) if protocols.contains(&protocol.as_str()) => Some(protocol.to_string()),
+                    Some(_) => return Err(invalid("Server chose a subprotocol that wasn't offered")),
+                    None => None
+                };
+
+                Ok(Client {
+                    stream,
+                    protocol,
+                    parser: Mutex::new(Parser::new(DEFAULT_MAX_MESSAGE_SIZE).client())
+                })
+            }
+
+            pub fn stream(&self) -> &TcpStream {
+                &self.stream
+            }
+
+            /// Subprotocol chosen by the server
+            pub fn protocol(&self) -> Option<&str> {
+                self.protocol.as_deref()
+            }
+
+            /// Read next message, see `Parser::parse`
+            pub fn receive(&self) -> Result<Message, WebSocketError> {
+                self.parser.lock().unwrap_or_else(|err| err.into_inner()).parse(&self.stream)
+            }
+
+            pub fn send(&self, msg: Message) -> Result<(), Error> {
+                let frame = to_frame(msg, None)?.masked(masking_key());
+
+                (&self.stream).write_all(&frame.payload)
+            }
+
+            /// See `super::close`
+            pub fn close(&self, code: CloseCode, reason: &str) -> Result<(), Error> {
+                let frame = Frame::close(Some(&CloseFrame::new(code, reason))).masked(masking_key());
+
+                close_with(&self.stream, frame)
+            }
+        }
+
+        /// Read response head byte by byte, so frames sent
+        /// right after it are left in the stream for the parser
+        fn read_head(mut stream: &TcpStream) -> Result<String, Error> {
+            let mut head = vec![];
+
+            while !head.ends_with(b"\r\n\r\n") {
+                if head.len() > MAX_HEAD_SIZE {
+                    return Err(invalid("Response head too long"));
+                }
+
+                let mut byte = [0; 1];
+                stream.read_exact(&mut byte)?;
+                head.push(byte[0]);
+            }
+
+            String::from_utf8(head).map_err(|_| invalid("Response head isn't UTF-8"))
+        }
+
+        fn invalid(reason: &str) -> Error {
+            Error::new(ErrorKind::InvalidData, reason)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::io::BufReader;
+        use std::net::TcpListener;
+        use crate::parser::websocket::DEFAULT_MAX_MESSAGE_SIZE;
+
+        #[test]
+        fn test_close_waits_for_answer_and_shuts_down() {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (server, _) = listener.accept().unwrap();
+
+            let closer = thread::spawn(move || close(&server, CloseCode::GOING_AWAY, "bye"));
+
+            let (header, payload) = parser::websocket::parse_frame(&mut client, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+            assert_eq!(header.opcode, Opcode::CLOSE);
+            assert_eq!(CloseFrame::from_payload(&payload),
+                       Some(CloseFrame::new(CloseCode::GOING_AWAY, "bye")));
+
+            client.write_all(&[0x88, 0x82, 0, 0, 0, 0, 0x03, 0xE9]).unwrap();
+            closer.join().unwrap().unwrap();
+
+            assert_eq!(client.read(&mut [0; 1]).unwrap(), 0);
+        }
+
+        #[test]
+        fn test_close_discards_payloads_without_buffering() {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (server, _) = listener.accept().unwrap();
+            let started = Instant::now();
+
+            let closer = thread::spawn(move || close(&server, CloseCode::NORMAL, ""));
+
+            parser::websocket::parse_frame(&mut client, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+
+            // binary frame announcing 64 TiB, far more than could ever be buffered
+            client.write_all(&[0x82, 0xFF, 0, 0, 0x40, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3]).unwrap();
+            client.shutdown(Shutdown::Write).unwrap();
+            closer.join().unwrap().unwrap();
+
+            assert!(started.elapsed() < CLOSE_TIMEOUT);
+        }
+
+        struct Echo {}
+
+        impl Handshake for Echo {
+            fn protocols(&self) -> &[&str] {
+                &["echo"]
+            }
+
+            fn allowed_origins(&self) -> &[&str] {
+                &["https://example.com"]
+            }
+
+            fn deflate(&self) -> Option<deflate::Config> {
+                Some(deflate::Config::default())
+            }
+        }
+
+        impl Communicator<String> for Echo {
+            fn receive(&self, connection: &Connection) -> Result<Option<String>, Error> {
+                match connection.receive()? {
+                    Message::Text(text) => Ok(Some(text)),
+                    _ => Ok(None)
+                }
+            }
+
+            fn send(&self, connection: &Connection, msg: String) -> Result<(), Error> {
+                connection.send(Message::Text(msg))
+            }
+        }
+
+        const HANDSHAKE: &str = "GET / HTTP/1.1\r\nHost: localhost\r\n\
+                                 Connection: Upgrade\r\nUpgrade: websocket\r\n\
+                                 Sec-WebSocket-Version: 13\r\n\
+                                 Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n";
+
+        /// Send handshake with extra headers to an echo chamber, returning
+        /// the client end, response head and the chamber
+        fn handshake(headers: &str) -> (TcpStream, String, JoinHandle<Result<(), Error>>) {
+            open(headers, |stream, request| echo_chamber(stream, request, Echo {}))
+        }
+
+        fn open<F>(headers: &str, serve: F) -> (TcpStream, String, JoinHandle<Result<(), Error>>)
+        where F: FnOnce(&TcpStream, http::request::Request) -> Result<(), Error> + Send + 'static {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (server, _) = listener.accept().unwrap();
+
+            let chamber = thread::spawn(move || {
+                let request = parser::request::parse(BufReader::new(&server)).unwrap();
+
+                serve(&server, request)
+            });
+
+            client.write_all(format!("{}{}\r\n", HANDSHAKE, headers).as_bytes()).unwrap();
+
+            let mut response = vec![];
+
+            while !response.ends_with(b"\r\n\r\n") {
+                let mut byte = [0; 1];
+                client.read_exact(&mut byte).unwrap();
+                response.push(byte[0]);
+            }
+
+            (client, String::from_utf8(response).unwrap(), chamber)
+        }
+
+        fn refused(headers: &str) -> String {
+            let (_, response, chamber) = handshake(headers);
+
+            assert_eq!(chamber.join().unwrap().unwrap_err().kind(), ErrorKind::InvalidData);
+
+            response
+        }
+
+        #[test]
+        fn test_handshake_version() {
+            let response = refused("Sec-WebSocket-Version: 8\r\n");
+
+            assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+            assert!(response.contains("Sec-WebSocket-Version: 13\r\n"));
+        }
+
+        #[test]
+        fn test_handshake_key() {
+            assert!(refused("Sec-WebSocket-Key: not base64\r\n").starts_with("HTTP/1.1 400 "));
+            assert!(refused("Sec-WebSocket-Key: c2hvcnQ=\r\n").starts_with("HTTP/1.1 400 "));
+        }
+
+        #[test]
+        fn test_handshake_origin() {
+            assert!(refused("Origin: https://evil.example\r\n").starts_with("HTTP/1.1 403 "));
+
+            let (_, response, _) = handshake("Origin: https://Example.com\r\n");
+
+            assert!(response.starts_with("HTTP/1.1 101 "));
+        }
+
+        #[test]
+        fn test_handshake_without_protocol() {
+            let (_, response, _) = handshake("");
+
+            assert!(response.starts_with("HTTP/1.1 101 "));
+            assert!(!response.contains("Sec-WebSocket-Protocol"));
+            assert!(!response.contains("Sec-WebSocket-Extensions"));
+        }
+
+        #[test]
+        fn test_echo_chamber_deflate() {
+            let (mut client, response, chamber) =
+                handshake("Sec-WebSocket-Protocol: chat, echo\r\n\
+                           Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n");
+
+            assert!(response.contains("Sec-WebSocket-Protocol: echo\r\n"));
+            assert!(response.contains("Sec-WebSocket-Extensions: permessage-deflate\r\n"));
+
+            // masked and compressed "Hello", RFC 7692 section 7.2.3.1
+            client.write_all(&[0xC1, 0x87, 0, 0, 0, 0, 0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00]).unwrap();
+
+            let (header, payload) = parser::websocket::parse_frame(&mut client, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+
+            assert_eq!(header.rsv, 0x4);
+            assert_eq!(deflate::Config::default().inflater().decompress(&payload, 1024).unwrap(), b"Hello");
+
+            client.write_all(&[0x88, 0x80, 0, 0, 0, 0]).unwrap();
+
+            assert_eq!(parser::websocket::parse_frame(&mut client, DEFAULT_MAX_MESSAGE_SIZE).unwrap().0.opcode, Opcode::CLOSE);
+            chamber.join().unwrap().unwrap();
+        }
+
+        /// Greets on open, echoes text and hands its sender and events over to the test
+        struct Pusher {
+            senders: mpsc::Sender<Sender>,
+            events: mpsc::Sender<String>,
+            keepalive: Option<Keepalive>
+        }
+
+        impl Handshake for Pusher {
+            fn keepalive(&self) -> Option<Keepalive> {
+                self.keepalive
+            }
+        }
+
+        impl Events for Pusher {
+            fn on_open(&self, sender: &Sender) {
+                sender.text("welcome").unwrap();
+                self.senders.send(sender.clone()).unwrap();
+            }
+
+            fn on_message(&self, sender: &Sender, msg: Message) {
+                if let Message::Text(text) = msg {
+                    sender.text(text).unwrap();
+                }
+            }
+
+            fn on_close(&self, close: Option<&CloseFrame>) {
+                self.events.send(format!("close {:?}", close.map(|close| close.code.code()))).unwrap();
+            }
+
+            fn on_error(&self, err: &WebSocketError) {
+                self.events.send(format!("error {}", err)).unwrap();
+            }
+        }
+
+        fn open_session(keepalive: Option<Keepalive>)
+                        -> (TcpStream, Sender, mpsc::Receiver<String>, JoinHandle<Result<(), Error>>) {
+            let (senders, sender) = mpsc::channel();
+            let (events, received) = mpsc::channel();
+            let pusher = Pusher { senders, events, keepalive };
+
+            let (client, response, session) = open("", |stream, request| super::session(stream, request, pusher));
+
+            assert!(response.starts_with("HTTP/1.1 101 "));
+
+            (client, sender.recv().unwrap(), received, session)
+        }
+
+        fn read_text(client: &mut TcpStream) -> String {
+            let (header, payload) = parser::websocket::parse_frame(client, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+
+            assert_eq!(header.opcode, Opcode::TEXT);
+
+            String::from_utf8(payload).unwrap()
+        }
+
+        #[test]
+        fn test_session_pushes_and_echoes() {
+            let (mut client, sender, events, session) = open_session(None);
+
+            assert_eq!(read_text(&mut client), "welcome");
+
+            thread::spawn(move || sender.text("pushed").unwrap()).join().unwrap();
+            assert_eq!(read_text(&mut client), "pushed");
+
+            client.write_all(&[0x89, 0x82, 0, 0, 0, 0, b'h', b'i']).unwrap();
+            assert_eq!(parser::websocket::parse_frame(&mut client, DEFAULT_MAX_MESSAGE_SIZE).unwrap().1, b"hi");
+
+            client.write_all(&[0x81, 0x82, 0, 0, 0, 0, b'h', b'i']).unwrap();
+            assert_eq!(read_text(&mut client), "hi");
+
+            client.write_all(&[0x88, 0x82, 0, 0, 0, 0, 0x03, 0xE8]).unwrap();
+
+            let (header, payload) = parser::websocket::parse_frame(&mut client, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+
+            assert_eq!(header.opcode, Opcode::CLOSE);
+            assert_eq!(payload, vec![0x03, 0xE8]);
+
+            session.join().unwrap().unwrap();
+
+            assert_eq!(events.recv().unwrap(), "close Some(1000)");
+            assert_eq!(client.read(&mut [0; 1]).unwrap(), 0);
+        }
+
+        #[test]
+        fn test_session_closed_by_server() {
+            let (mut client, sender, events, session) = open_session(None);
+
+            assert_eq!(read_text(&mut client), "welcome");
+
+            sender.close(CloseCode::GOING_AWAY, "bye").unwrap();
+
+            let (header, payload) = parser::websocket::parse_frame(&mut client, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+
+            assert_eq!(header.opcode, Opcode::CLOSE);
+            assert_eq!(CloseFrame::from_payload(&payload), Some(CloseFrame::new(CloseCode::GOING_AWAY, "bye")));
+
+            client.write_all(&[0x88, 0x82, 0, 0, 0, 0, 0x03, 0xE9]).unwrap();
+            session.join().unwrap().unwrap();
+
+            assert_eq!(events.recv().unwrap(), "close Some(1001)");
+            assert!(sender.text("too late").is_err());
+        }
+
+        #[test]
+        fn test_session_closed_by_client_not_reading() {
+            let (mut client, sender, events, session) = open_session(None);
+
+            // fill the socket buffers and then the queue, the writer blocks on them
+            for _ in 0..2 {
+                while sender.try_send(Message::Text("a".repeat(65536))).is_ok() {}
+                thread::sleep(Duration::from_millis(100));
+            }
+
+            let started = Instant::now();
+
+            client.write_all(&[0x88, 0x82, 0, 0, 0, 0, 0x03, 0xE8]).unwrap();
+            session.join().unwrap().unwrap();
+
+            assert_eq!(events.recv().unwrap(), "close Some(1000)");
+            assert!(started.elapsed() >= CLOSE_TIMEOUT && started.elapsed() < CLOSE_TIMEOUT * 2);
+        }
+
+        #[test]
+        fn test_session_protocol_error() {
+            let (mut client, _, events, session) = open_session(None);
+
+            assert_eq!(read_text(&mut client), "welcome");
+
+            // unmasked frame from client
+            client.write_all(&[0x81, 0x02, b'h', b'i']).unwrap();
+
+            let (_, payload) = parser::websocket::parse_frame(&mut client, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+
+            assert_eq!(payload, vec![0x03, 0xEA]);
+            assert!(session.join().unwrap().is_err());
+            assert_eq!(events.recv().unwrap(), "error Unmasked client frame");
+            assert_eq!(events.recv().unwrap(), "close None");
+        }
+
+        const QUICK: Keepalive = Keepalive {
+            interval: Duration::from_millis(50),
+            timeout: Duration::from_millis(300)
+        };
+
+        /// Answer pings for well past the timeout, then go silent
+        fn ping_pong(client: &mut TcpStream) -> Vec<u8> {
+            let started = Instant::now();
+
+            while started.elapsed() < QUICK.timeout * 2 {
+                let (header, _) = parser::websocket::parse_frame(client, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+
+                assert_eq!(header.opcode, Opcode::PING);
+                client.write_all(&[0x8A, 0x80, 0, 0, 0, 0]).unwrap();
+            }
+
+            loop {
+                match parser::websocket::parse_frame(client, DEFAULT_MAX_MESSAGE_SIZE).unwrap() {
+                    (header, _) if header.opcode == Opcode::PING => {},
+                    (header, payload) => {
+                        assert_eq!(header.opcode, Opcode::CLOSE);
+
+                        break payload;
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn test_session_keepalive() {
+            let (mut client, sender, events, session) = open_session(Some(QUICK));
+
+            assert_eq!(read_text(&mut client), "welcome");
+
+            let payload = ping_pong(&mut client);
+
+            assert_eq!(&payload[..2], &[0x03, 0xE9]);
+            assert!(sender.last_seen().elapsed() >= QUICK.timeout);
+
+            session.join().unwrap().unwrap();
+
+            assert_eq!(events.recv().unwrap(), "close None");
+            assert!(!sender.is_open());
+            assert_eq!(client.read(&mut [0; 1]).unwrap(), 0);
+        }
+
+        struct Idle {}
+
+        impl Handshake for Idle {
+            fn keepalive(&self) -> Option<Keepalive> {
+                Some(QUICK)
+            }
+        }
+
+        impl Communicator<Message> for Idle {
+            fn receive(&self, connection: &Connection) -> Result<Option<Message>, Error> {
+                Ok(Some(connection.receive()?))
+            }
+
+            fn send(&self, connection: &Connection, msg: Message) -> Result<(), Error> {
+                connection.send(msg)
+            }
+        }
+
+        struct Small {}
+
+        impl Handshake for Small {
+            fn max_message_size(&self) -> usize {
+                4
+            }
+        }
+
+        impl Communicator<Message> for Small {
+            fn receive(&self, connection: &Connection) -> Result<Option<Message>, Error> {
+                Ok(Some(connection.receive()?))
+            }
+
+            fn send(&self, connection: &Connection, msg: Message) -> Result<(), Error> {
+                connection.send(msg)
+            }
+        }
+
+        #[test]
+        fn test_echo_chamber_max_message_size() {
+            let (mut client, _, chamber) = open("", |stream, request| echo_chamber(stream, request, Small {}));
+
+            client.write_all(&[0x81, 0x84, 0, 0, 0, 0, b'f', b'o', b'u', b'r']).unwrap();
+            assert_eq!(read_text(&mut client), "four");
+
+            client.write_all(&[0x81, 0x85, 0, 0, 0, 0, b'f', b'i', b'v', b'e', b'!']).unwrap();
+
+            let (header, payload) = parser::websocket::parse_frame(&mut client, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+
+            assert_eq!(header.opcode, Opcode::CLOSE);
+            assert_eq!(&payload[..2], &[0x03, 0xF1]);
+            assert!(chamber.join().unwrap().is_err());
+        }
+
+        #[test]
+        fn test_echo_chamber_keepalive() {
+            let (mut client, _, chamber) = open("", |stream, request| echo_chamber(stream, request, Idle {}));
+
+            client.write_all(&[0x81, 0x82, 0, 0, 0, 0, b'h', b'i']).unwrap();
+            assert_eq!(read_text(&mut client), "hi");
+
+            let payload = ping_pong(&mut client);
+
+            assert_eq!(&payload[..2], &[0x03, 0xE9]);
+            assert!(chamber.join().unwrap().is_err());
+        }
+
+        struct Typed {
+            codecs: codec::Codecs<serde_json::Value>
+        }
+
+        impl Handshake for Typed {
+            fn protocols(&self) -> &[&str] {
+                self.codecs.protocols()
+            }
+        }
+
+        impl Communicator<serde_json::Value> for Typed {
+            fn receive(&self, connection: &Connection) -> Result<Option<serde_json::Value>, Error> {
+                connection.receive_with(&self.codecs)
+            }
+
+            fn send(&self, connection: &Connection, msg: serde_json::Value) -> Result<(), Error> {
+                connection.send_with(&self.codecs, &msg)
+            }
+        }
+
+        #[test]
+        fn test_echo_chamber_codec() {
+            let typed = Typed { codecs: codec::Codecs::new(codec::Json) };
+            let (mut client, response, chamber) = open("Sec-WebSocket-Protocol: xml, json\r\n",
+                                                       |stream, request| echo_chamber(stream, request, typed));
+
+            assert!(response.contains("Sec-WebSocket-Protocol: json\r\n"));
+
+            client.write_all(&[0x81, 0x87, 0, 0, 0, 0, b'{', b'"', b'a', b'"', b':', b'1', b'}']).unwrap();
+            assert_eq!(read_text(&mut client), r#"{"a":1}"#);
+
+            client.write_all(&[0x81, 0x81, 0, 0, 0, 0, b'{']).unwrap();
+
+            let (header, payload) = parser::websocket::parse_frame(&mut client, DEFAULT_MAX_MESSAGE_SIZE).unwrap();
+
+            assert_eq!(header.opcode, Opcode::CLOSE);
+            assert_eq!(&payload[..2], &[0x03, 0xEF]);
+
+            client.write_all(&[0x88, 0x82, 0, 0, 0, 0, 0x03, 0xEF]).unwrap();
+            chamber.join().unwrap().unwrap();
+        }
+
+        fn serve_echo() -> server::Handle {
+            server::Server::new(server::Config::default())
+                .serve("127.0.0.1", 0, |stream: &TcpStream, request| echo_chamber(stream, request, Echo {}))
+                .unwrap()
+        }
+
+        #[test]
+        fn test_client_echo_chamber() {
+            let server = serve_echo();
+            let client = client::connect(&format!("ws://{}/ws", server.local_addr()), &["chat", "echo"]).unwrap();
+            let long = "a".repeat(70000);
+
+            assert_eq!(client.protocol(), Some("echo"));
+
+            client.send(Message::Text("hello".to_string())).unwrap();
+            assert_eq!(client.receive().unwrap(), Message::Text("hello".to_string()));
+
+            client.send(Message::Text(long.clone())).unwrap();
+            assert_eq!(client.receive().unwrap(), Message::Text(long));
+
+            client.close(CloseCode::NORMAL, "").unwrap();
+            server.shutdown(Duration::from_secs(1));
+        }
+
+        /// Stop server while client is connected, expecting it to be told with 1001
+        fn stop_connected(server: server::Handle, client: client::Client) {
+            let started = Instant::now();
+            let stopper = server.stopper();
+            let stopping = thread::spawn(move || stopper.stop(Duration::from_secs(10)));
+
+            assert_eq!(client.receive().unwrap(),
+                       Message::Close(Some(CloseFrame::new(CloseCode::GOING_AWAY, "Server stopping"))));
+
+            stopping.join().unwrap();
+            server.join();
+
+            assert!(started.elapsed() < CLOSE_TIMEOUT);
+        }
+
+        #[test]
+        fn test_stop_echo_chamber() {
+            let server = serve_echo();
+            let client = client::connect(&format!("ws://{}/ws", server.local_addr()), &[]).unwrap();
+
+            client.send(Message::Text("hello".to_string())).unwrap();
+            assert_eq!(client.receive().unwrap(), Message::Text("hello".to_string()));
+
+            stop_connected(server, client);
+        }
+
+        #[test]
+        fn test_stop_session() {
+            let (senders, _sender) = mpsc::channel();
+            let (events, received) = mpsc::channel();
+            let server = server::Server::new(server::Config::default())
+                .serve("127.0.0.1", 0, move |stream: &TcpStream, request| {
+                    session(stream, request, Pusher { senders: senders.clone(), events: events.clone(), keepalive: None })
+                })
+                .unwrap();
+            let client = client::connect(&format!("ws://{}/ws", server.local_addr()), &[]).unwrap();
+
+            assert_eq!(client.receive().unwrap(), Message::Text("welcome".to_string()));
+
+            stop_connected(server, client);
+
+            assert_eq!(received.recv().unwrap(), "close Some(1001)");
+        }
+
+        #[test]
+        fn test_client_refused() {
+            let server = server::Server::new(server::Config::default())
+                .serve("127.0.0.1", 0, |stream: &TcpStream, _| server::respond(stream, http::response::not_found()))
+                .unwrap();
+
+            let err = client::connect(&format!("ws://{}/", server.local_addr()), &[]).err().unwrap();
+
+            assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+        }
+
+        #[test]
+        fn test_client_checks_accept() {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+
+                parser::request::parse(BufReader::new(&stream)).unwrap();
+                stream.write_all(b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\n\
+                                   Upgrade: websocket\r\nSec-WebSocket-Accept: wrong\r\n\r\n").unwrap();
+            });
+
+            let err = client::connect(&format!("ws://{}", addr), &[]).err().unwrap();
+
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+
+        #[test]
+        fn test_client_url() {
+            assert_eq!(client::connect("wss://localhost/", &[]).err().unwrap().kind(), ErrorKind::InvalidInput);
+        }
+    }
+}
+
+pub mod hub {
+    use std::collections::{HashMap, HashSet};
+    use std::io::ErrorKind;
+    use std::sync::{Mutex, MutexGuard, PoisonError};
+
+    use super::websocket::Sender;
+    use crate::http::websocket::Message;
+
+    /// Sessions that can be reached from any other, e.g. to fan out server side events
+    /// to subscribers. Share it between handlers with `server::with_state`.
+    /// Messages are queued without blocking, so a session whose queue is full
+    /// misses them instead of holding up the rest
+    #[derive(Default)]
+    pub struct Hub {
+        members: Mutex<Members>
+    }
+
+    #[derive(Default)]
+    struct Members {
+        sessions: HashMap<usize, Sender>,
+        rooms: HashMap<String, HashSet<usize>>
+    }
+
+    impl Members {
+        fn remove(&mut self, id: usize) {
+            self.sessions.remove(&id);
+
+            for members in self.rooms.values_mut() {
+                members.remove(&id);
+            }
+
+            self.rooms.retain(|_, members| !members.is_empty());
+        }
+
+        /// Sessions end without telling the hub, so they are cleaned up whenever it changes
+        fn remove_ended(&mut self) {
+            let ended: Vec<usize> = self.sessions.values()
+                .filter(|sender| !sender.is_open())
+                .map(|sender| sender.id())
+                .collect();
+
+            for id in ended {
+                self.remove(id);
+            }
+        }
+    }
+
+    impl Hub {
+        pub fn new() -> Hub {
+            Hub::default()
+        }
+
+        fn members(&self) -> MutexGuard<'_, Members> {
+            self.members.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        /// Track session so that broadcasts reach it, e.g. in `on_open`
+        pub fn add(&self, sender: &Sender) {
+            let mut members = self.members();
+
+            members.remove_ended();
+            members.sessions.insert(sender.id(), sender.clone());
+        }
+
+        /// Stop tracking session, leaving all of its rooms
+        pub fn remove(&self, sender: &Sender) {
+            self.members().remove(sender.id());
+        }
+
+        /// Add session to a room, which is created on first join
+        pub fn join(&self, sender: &Sender, room: &str) {
+            let mut members = self.members();
+
+            members.remove_ended();
+            members.sessions.insert(sender.id(), sender.clone());
+            members.rooms.entry(room.to_string()).or_default().insert(sender.id());
+        }
+
+        /// Room is removed once its last member leaves
+        pub fn leave(&self, sender: &Sender, room: &str) {
+            let mut members = self.members();
+
+            if let Some(room_members) = members.rooms.get_mut(room) {
+                room_members.remove(&sender.id());
+
+                if room_members.is_empty() {
+                    members.rooms.remove(room);
+                }
+            }
+        }
+
+        /// Number of sessions tracked
+        pub fn len(&self) -> usize {
+            self.members().sessions.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.len() == 0
+        }
+
+        /// Number of sessions in a room
+        pub fn room_len(&self, room: &str) -> usize {
+            self.members().rooms.get(room).map(|members| members.len()).unwrap_or(0)
+        }
+
+        /// Send to every session, returns how many the message was queued for
+        pub fn broadcast(&self, msg: Message) -> usize {
+            self.deliver(msg, None, None)
+        }
+
+        /// Send to sessions in a room
+        pub fn broadcast_to(&self, room: &str, msg: Message) -> usize {
+            self.deliver(msg, Some(room), None)
+        }
+
+        /// Send to every session but the sender, e.g. to pass on what it sent
+        pub fn broadcast_except(&self, sender: &Sender, msg: Message) -> usize {
+            self.deliver(msg, None, Some(sender.id()))
+        }
+
+        fn deliver(&self, msg: Message, room: Option<&str>, except: Option<usize>) -> usize {
+            let mut members = self.members();
+
+            let ids: Vec<usize> = match room {
+                Some(room) => members.rooms.get(room).map(|ids| ids.iter().copied().collect()).unwrap_or_default(),
+                None => members.sessions.keys().copied().collect()
+            };
+
+            let mut delivered = 0;
+            let mut ended = vec![];
+
+            for id in ids.into_iter().filter(|id| Some(*id) != except) {
+                match members.sessions[&id].try_send(msg.clone()) {
+                    Ok(_) => delivered += 1,
+                    // slow consumer misses the message
+                    Err(ref err) if err.kind() == ErrorKind::WouldBlock => {},
+                    Err(_) => ended.push(id)
+                }
+            }
+
+            for id in ended {
+                members.remove(id);
+            }
+
+            delivered
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::sync::mpsc;
+        use crate::web::websocket::Outgoing;
+
+        fn received(outgoing: &mpsc::Receiver<Outgoing>) -> Vec<Message> {
+            outgoing.try_iter()
+                .filter_map(|outgoing| match outgoing {
+                    Outgoing::Message(msg) => Some(msg),
+                    _ => None
+                })
+                .collect()
+        }
+
+        fn text(text: &str) -> Message {
+            Message::Text(text.to_string())
+        }
+
+        #[test]
+        fn test_broadcast() {
+            let hub = Hub::new();
+            let (first, first_out) = Sender::detached(8);
+            let (second, second_out) = Sender::detached(8);
+
+            hub.add(&first);
+            hub.add(&second);
+
+            assert_eq!(hub.broadcast(text("all")), 2);
+            assert_eq!(hub.broadcast_except(&first, text("others")), 1);
+
+            assert_eq!(received(&first_out), vec![text("all")]);
+            assert_eq!(received(&second_out), vec![text("all"), text("others")]);
+        }
+
+        #[test]
+        fn test_rooms() {
+            let hub = Hub::new();
+            let (first, first_out) = Sender::detached(8);
+            let (second, second_out) = Sender::detached(8);
+
+            hub.join(&first, "news");
+            hub.join(&second, "news");
+            hub.join(&second, "sports");
+
+            assert_eq!(hub.broadcast_to("sports", text("goal")), 1);
+
+            hub.leave(&second, "news");
+
+            assert_eq!(hub.broadcast_to("news", text("headline")), 1);
+            assert_eq!(hub.broadcast_to("weather", text("rain")), 0);
+
+            assert_eq!(received(&first_out), vec![text("headline")]);
+            assert_eq!(received(&second_out), vec![text("goal")]);
+
+            hub.remove(&second);
+
+            assert_eq!(hub.room_len("sports"), 0);
+            assert_eq!(hub.len(), 1);
+        }
+
+        #[test]
+        fn test_slow_consumer_misses_messages() {
+            let hub = Hub::new();
+            let (slow, slow_out) = Sender::detached(1);
+            let (fast, fast_out) = Sender::detached(8);
+
+            hub.add(&slow);
+            hub.add(&fast);
+
+            assert_eq!(hub.broadcast(text("first")), 2);
+            assert_eq!(hub.broadcast(text("second")), 1);
+
+            assert_eq!(received(&slow_out), vec![text("first")]);
+            assert_eq!(received(&fast_out), vec![text("first"), text("second")]);
+            assert_eq!(hub.len(), 2);
+        }
+
+        #[test]
+        fn test_ended_sessions_are_removed() {
+            let hub = Hub::new();
+            let (gone, gone_out) = Sender::detached(8);
+            let (staying, _staying_out) = Sender::detached(8);
+
+            hub.join(&gone, "room");
+            hub.join(&staying, "room");
+            drop(gone_out);
 
-        match (request.generate_websocket_accept_value(), is_ok) {
-            (Some(key), true) =>
-                server::respond(stream,
-                                http::response::websocket(key, protocol.to_string())),
-            _ => Err(Error::new(ErrorKind::ConnectionAborted, ""))
+            assert_eq!(hub.broadcast_to("room", text("hello")), 1);
+            assert_eq!(hub.len(), 1);
+            assert_eq!(hub.room_len("room"), 1);
         }
     }
 }